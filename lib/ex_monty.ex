@@ -41,6 +41,20 @@ defmodule ExMonty do
       end
 
   See `ExMonty.Sandbox` for a high-level handler that automates the interactive loop.
+
+  ## Errors
+
+  Functions fail with `{:error, reason}`, where `reason` is an
+  `ExMonty.Exception` for Python exceptions, exceeded limits and compile
+  errors, and an `ExMonty.Error` for everything else. Both carry a `:kind`
+  from one taxonomy, documented in `ExMonty.Error`:
+
+      case ExMonty.run(runner, inputs) do
+        {:ok, result, _output} -> result
+        {:error, %{kind: :limit_exceeded}} -> :too_expensive
+        {:error, %{kind: :input_error, details: %{path: path}}} -> {:bad_input, path}
+        {:error, %{kind: kind, message: message}} -> {kind, message}
+      end
   """
 
   alias ExMonty.Native
@@ -48,27 +62,56 @@ defmodule ExMonty do
   @type runner :: reference()
   @type snapshot :: reference()
   @type future_snapshot :: reference()
-  @type error_reason :: term()
-
-  @type limits :: %{
-          optional(:max_allocations) => non_neg_integer(),
-          optional(:max_duration_secs) => float(),
-          optional(:max_memory) => non_neg_integer(),
-          optional(:gc_interval) => non_neg_integer(),
-          optional(:max_recursion_depth) => non_neg_integer()
+  @type cancel_token :: reference()
+  @type session :: reference()
+  @type error_reason :: ExMonty.Error.t() | ExMonty.Exception.t()
+
+  @type limits ::
+          %{
+            optional(:max_allocations) => pos_integer(),
+            optional(:max_duration_secs) => number(),
+            optional(:time_budget) => :cpu | :wall,
+            optional(:max_memory) => pos_integer(),
+            optional(:gc_interval) => pos_integer(),
+            optional(:max_recursion_depth) => pos_integer(),
+            optional(:max_output_bytes) => pos_integer(),
+            optional(:output_limit_policy) => :error | :truncate
+          }
+          | keyword()
+
+  @type stats :: %{
+          allocations: non_neg_integer(),
+          memory: non_neg_integer(),
+          peak_memory: non_neg_integer(),
+          max_recursion_depth: non_neg_integer(),
+          elapsed_secs: float(),
+          cpu_secs: float()
         }
 
+  @type batch_result ::
+          {:ok, term(), String.t()}
+          | {:ok, term(), String.t(), stats()}
+          | {:error, error_reason()}
+
   @type progress ::
           {:function_call, ExMonty.FunctionCall.t(), snapshot(), String.t()}
           | {:os_call, ExMonty.OsCall.t(), snapshot(), String.t()}
           | {:resolve_futures, future_snapshot(), String.t()}
           | {:complete, term(), String.t()}
+          | {:function_call, ExMonty.FunctionCall.t(), snapshot(), String.t(), stats()}
+          | {:os_call, ExMonty.OsCall.t(), snapshot(), String.t(), stats()}
+          | {:resolve_futures, future_snapshot(), String.t(), stats()}
+          | {:complete, term(), String.t(), stats()}
 
   @doc """
   Compiles Python code into a reusable runner.
 
   The runner can be executed multiple times with different inputs via `run/3` or `start/3`.
 
+  Code that fails to compile returns `{:error, %ExMonty.Exception{}}` whose
+  `:diagnostics` list the problem with its line, column and end span (see
+  `ExMonty.Diagnostic`).
+
   ## Options
 
     * `:inputs` - list of input variable names (default: `[]`)
@@ -83,6 +126,9 @@ defmodule ExMonty do
         inputs: ["url"],
         external_functions: ["fetch"]
       )
+
+      {:error, %ExMonty.Exception{type: :syntax_error, diagnostics: [diagnostic]}} =
+        ExMonty.compile("x = (1 +")
   """
   @spec compile(String.t(), keyword()) :: {:ok, runner()} | {:error, error_reason()}
   def compile(code, opts \\ []) do
@@ -111,23 +157,207 @@ defmodule ExMonty do
   ## Options
 
     * `:limits` - resource limits map (default: `nil` for default limits)
+    * `:print_to` - stream `print()` output while the script runs (see "Streaming Output")
+    * `:cancel` - a token from `cancel_token/1` that stops the script (see "Cancellation")
+    * `:stats` - when `true`, also return a resource usage map (see "Usage Statistics")
+    * `:dict_format` - how Python dicts are returned (see "Dict Format")
+    * `:atom_policy` - how atoms in inputs are decoded (see "Atom Policy")
+    * `:keyword_lists`, `:charlists` - opt-in list conversions (see "Lists")
+    * `:float_atoms` - decode `:nan` and the infinities as floats (see "Special Floats")
+
+  ## Limits
+
+  `:limits` is a map or keyword list using the keys of `t:limits/0`. It is
+  validated strictly: unknown keys, duplicate keys and values that are not
+  positive return an `ExMonty.Error` of kind `:invalid_option`, naming the
+  key in `details.option`, rather than running without the limit.
+  `max_duration_secs` accepts integers as well as floats, and `nil` leaves a
+  limit unset.
+
+  ## Time Budget
+
+  `max_duration_secs` bounds the whole execution, not each step. How time is
+  counted across `start/3`, `resume/3` and `resume_futures/3` is set with
+  `:time_budget` in the limits map:
+
+    * `:wall` (default) - wall-clock time since `start/3`, including time
+      between steps. For `run/3` this is a plain wall-clock timeout
+    * `:cpu` - only CPU time used by the interpreter, summed over all steps;
+      time spent in Elixir handlers between steps, or waiting for a CPU, is
+      free. On platforms without a per-thread CPU clock this falls back to
+      wall time spent in the interpreter
+
+  Use `remaining_budget/1` to see what is left before resuming.
+
+  ## Output Limit
+
+  `limits: %{max_output_bytes: n}` caps the total `print()` output of an
+  execution. With the default `output_limit_policy: :error` the script is
+  stopped and an `%ExMonty.Exception{}` with `limit: {:output_limit, limit, written}`
+  is returned. With
+  `output_limit_policy: :truncate` the script keeps running, output past the
+  cap is dropped and a `"[output truncated]"` marker is appended once. For
+  interactive execution the cap set in `start/3` covers all later `resume/3`
+  and `resume_futures/3` steps.
+
+  ## Streaming Output
+
+  With `print_to: {pid, ref}`, every completed line of output is sent to `pid`
+  as `{:ex_monty_print, ref, chunk}` as soon as it is printed. A bare pid may be
+  given, in which case `ref` is `nil`. The full output is still returned when
+  execution finishes.
+
+  ## Dict Format
+
+  Erlang maps do not keep Python's dict insertion order. `:dict_format`
+  chooses how dicts in results and in external call arguments are returned:
+
+    * `:map` (default) - an Elixir map, or `{:dict, pairs}` when two keys
+      would become the same Elixir term (such as two `float('nan')` keys)
+    * `:pairs` - a list of `{key, value}` tuples in insertion order
+    * `:tagged` - `{:dict, pairs}`, which also decodes back into a dict, so an
+      ordered dict can be passed into Python unchanged
+
+  ## Atom Policy
+
+  Atoms other than `nil`, `true`, `false` and `:ellipsis` have no Python
+  equivalent. `:atom_policy` chooses how they are decoded, in inputs and in
+  handler results:
+
+    * `:string` (default) - a `str` with the atom's name, so `:ok` becomes `"ok"`
+    * `:strict` - an `:input_error` naming where the atom was found, such as
+      `"unsupported atom :ok at x[0]"`, with the path in `details.path`
+    * `:tagged` - an `Atom(name='ok')` object, distinct from any string, that
+      is returned to Elixir as the atom again
+
+  ## Lists
+
+  Lists in inputs and handler results are Python lists. Two conversions make
+  idiomatic Elixir values usable directly:
+
+    * `keyword_lists: :dict` - keyword lists such as `[a: 1, b: 2]` become
+      dicts with string keys (`{'a': 1, 'b': 2}`); the first value of a
+      repeated key wins, as with `Keyword.get/2`
+    * `charlists: :string` - lists of printable code points such as `'abc'`
+      become strings
+
+  Both default to `:list`. An improper list is always rejected with an
+  `:input_error` naming where it was found, such as
+  `"improper list at x[\"items\"]"`.
+
+  ## Special Floats
+
+  NaN and infinite floats are returned as the atoms `:nan`, `:infinity` and
+  `:neg_infinity`, since Erlang floats cannot hold them. Those atoms are
+  decoded like any other atom (see "Atom Policy") unless `float_atoms: :float`
+  is given, which turns them back into floats so such results can be passed
+  into Python again. The default is `float_atoms: :atom`.
+
+  ## Cancellation
+
+  Passing `cancel: token` lets any process stop the script with `cancel/1`.
+  The interpreter checks the token at its next time or allocation check and
+  the call returns an `ExMonty.Error` of kind `:cancelled`; the script cannot
+  catch it. The
+  same token can be passed to every step of an interactive execution.
+
+  ## Usage Statistics
+
+  With `stats: true` the result is `{:ok, result, output, stats}`, where `stats`
+  is a map of:
+
+    * `:allocations` - number of heap allocations
+    * `:memory` - bytes still allocated when execution stopped
+    * `:peak_memory` - highest number of bytes allocated at once
+    * `:max_recursion_depth` - deepest call stack reached
+    * `:elapsed_secs` - wall time spent in the interpreter
+    * `:cpu_secs` - CPU time used by the interpreter, as counted by the
+      `:cpu` time budget
+
+  For interactive execution the same map is appended to every progress tuple
+  and is cumulative from `start/3`; time spent in Elixir between steps is not
+  included in `:elapsed_secs` or `:cpu_secs`.
 
   ## Examples
 
       {:ok, runner} = ExMonty.compile("result = x + y", inputs: ["x", "y"])
       {:ok, result, output} = ExMonty.run(runner, %{"x" => 1, "y" => 2})
       # result = 3, output = ""
+
+      ref = make_ref()
+      {:ok, runner} = ExMonty.compile("print('working')")
+      {:ok, nil, _output} = ExMonty.run(runner, %{}, print_to: {self(), ref})
+      # receive do {:ex_monty_print, ^ref, chunk} -> IO.write(chunk) end
   """
   @spec run(runner(), map(), keyword()) ::
-          {:ok, term(), String.t()} | {:error, error_reason()}
+          {:ok, term(), String.t()}
+          | {:ok, term(), String.t(), stats()}
+          | {:error, error_reason()}
   def run(runner, inputs \\ %{}, opts \\ []) do
     limits = Keyword.get(opts, :limits, nil)
     input_list = Enum.map(inputs, fn {k, v} -> {to_string(k), v} end)
 
-    case Native.run(runner, input_list, limits) do
+    case Native.run(runner, input_list, limits, native_opts(opts)) do
+      {:ok, _result, _output} = ok -> ok
+      {:ok, _result, _output, _stats} = ok -> ok
       {:error, reason} -> {:error, reason}
-      {:ok, {result, output}} -> {:ok, result, output}
-      {result, output} when is_binary(output) -> {:ok, result, output}
+    end
+  rescue
+    e in ErlangError ->
+      {:error, e.original}
+  end
+
+  @doc """
+  Runs a compiled runner once for each input map in `batch`, in one NIF call.
+
+  Returns `{:ok, results}` with one entry per input map, in order: whatever
+  `run/3` would have returned for that item. A failing item does not affect
+  the others; an item that is not a map of inputs fails with an
+  `:input_error` whose path is `batch[index]`. Limits apply to each item
+  separately.
+
+  ## Options
+
+    * `:limits` - resource limits for each item (see `run/3`)
+    * `:max_concurrency` - number of threads to run items on (default: `1`).
+      Capped at the number of CPUs
+    * `:cancel` - a token from `cancel_token/1`; items still running or queued
+      when it is cancelled fail with kind `:cancelled`
+    * `:stats` - include a usage map in each successful result (see `run/3`)
+    * `:dict_format` - how dicts are returned (see `run/3`)
+    * `:atom_policy` - how atoms in inputs are decoded (see `run/3`)
+    * `:keyword_lists`, `:charlists` - opt-in list conversions (see `run/3`)
+    * `:float_atoms` - decode `:nan` and the infinities as floats (see `run/3`)
+
+  Print output is returned per item; `:print_to` is not supported.
+
+  ## Examples
+
+      {:ok, runner} = ExMonty.compile("x * 2", inputs: ["x"])
+      {:ok, results} = ExMonty.run_batch(runner, [%{"x" => 1}, %{"x" => 2}])
+      # results = [{:ok, 2, ""}, {:ok, 4, ""}]
+  """
+  @spec run_batch(runner(), [map()], keyword()) ::
+          {:ok, [batch_result()]} | {:error, error_reason()}
+  def run_batch(runner, batch, opts \\ []) do
+    limits = Keyword.get(opts, :limits, nil)
+
+    input_lists = Enum.map(batch, &batch_inputs/1)
+
+    batch_opts = %{
+      max_concurrency: Keyword.get(opts, :max_concurrency),
+      cancel: Keyword.get(opts, :cancel),
+      stats: Keyword.get(opts, :stats, false),
+      dict_format: Keyword.get(opts, :dict_format),
+      atom_policy: Keyword.get(opts, :atom_policy),
+      keyword_lists: Keyword.get(opts, :keyword_lists),
+      charlists: Keyword.get(opts, :charlists),
+      float_atoms: Keyword.get(opts, :float_atoms)
+    }
+
+    case Native.run_batch(runner, input_lists, limits, batch_opts) do
+      {:error, reason} -> {:error, reason}
+      results when is_list(results) -> {:ok, results}
     end
   rescue
     e in ErlangError ->
@@ -143,6 +373,13 @@ defmodule ExMonty do
 
     * `:inputs` - map of input variable names to values (default: `%{}`)
     * `:limits` - resource limits map (default: `nil`)
+    * `:print_to` - stream `print()` output while running (see `run/3`)
+    * `:cancel` - a token from `cancel_token/1` (see `run/3`)
+    * `:stats` - also return a resource usage map (see `run/3`)
+    * `:dict_format` - how dicts are returned (see `run/3`)
+    * `:atom_policy` - how atoms in inputs are decoded (see `run/3`)
+    * `:keyword_lists`, `:charlists` - opt-in list conversions (see `run/3`)
+    * `:float_atoms` - decode `:nan` and the infinities as floats (see `run/3`)
     * `:script_name` - script name for tracebacks (default: `"main.py"`)
 
   ## Examples
@@ -153,11 +390,13 @@ defmodule ExMonty do
         inputs: %{"x" => 10, "y" => 20}
       )
   """
-  @spec eval(String.t(), keyword()) :: {:ok, term(), String.t()} | {:error, error_reason()}
+  @spec eval(String.t(), keyword()) ::
+          {:ok, term(), String.t()}
+          | {:ok, term(), String.t(), stats()}
+          | {:error, error_reason()}
   def eval(code, opts \\ []) do
     inputs = Keyword.get(opts, :inputs, %{})
     input_names = inputs |> Map.keys() |> Enum.map(&to_string/1) |> Enum.sort()
-    limits = Keyword.get(opts, :limits, nil)
     script_name = Keyword.get(opts, :script_name, "main.py")
 
     compile_opts = [
@@ -167,7 +406,7 @@ defmodule ExMonty do
     ]
 
     with {:ok, runner} <- compile(code, compile_opts) do
-      run(runner, inputs, limits: limits)
+      run(runner, inputs, Keyword.drop(opts, [:inputs, :external_functions, :script_name]))
     end
   end
 
@@ -180,6 +419,13 @@ defmodule ExMonty do
   ## Options
 
     * `:limits` - resource limits map (default: `nil`)
+    * `:print_to` - stream `print()` output while running (see `run/3`)
+    * `:cancel` - a token from `cancel_token/1` (see `run/3`)
+    * `:stats` - append a resource usage map to the progress tuple (see `run/3`)
+    * `:dict_format` - how dicts are returned (see `run/3`)
+    * `:atom_policy` - how atoms in inputs are decoded (see `run/3`)
+    * `:keyword_lists`, `:charlists` - opt-in list conversions (see `run/3`)
+    * `:float_atoms` - decode `:nan` and the infinities as floats (see `run/3`)
 
   ## Progress Values
 
@@ -206,7 +452,7 @@ defmodule ExMonty do
     limits = Keyword.get(opts, :limits, nil)
     input_list = Enum.map(inputs, fn {k, v} -> {to_string(k), v} end)
 
-    case Native.start(runner, input_list, limits) do
+    case Native.start(runner, input_list, limits, native_opts(opts)) do
       {:error, reason} -> {:error, reason}
       {:ok, progress} -> {:ok, progress}
       progress when is_tuple(progress) -> {:ok, progress}
@@ -220,17 +466,36 @@ defmodule ExMonty do
   Resumes interactive execution from a snapshot with a result value.
 
   The result should be `{:ok, value}` for successful returns or
-  `{:error, type, message}` for errors.
+  `{:error, type, message}` for errors, where `type` is a built-in exception
+  name such as `:value_error`; an unknown name returns an error instead of
+  resuming. `{:error, %ExMonty.Exception{}}` raises an exception built from
+  its `:type` and `:message` or `:args` (see `ExMonty.Exception`). Any other
+  `{:error, ...}` shape, or a message that is not a string, returns an
+  `:input_error`.
+
+  ## Options
+
+    * `:print_to` - stream `print()` output while running (see `run/3`)
+    * `:cancel` - a token from `cancel_token/1` (see `run/3`)
+    * `:stats` - append a resource usage map to the progress tuple (see `run/3`)
+    * `:dict_format` - how dicts are returned (see `run/3`)
+    * `:atom_policy` - how atoms in the result are decoded (see `run/3`)
+    * `:keyword_lists`, `:charlists` - opt-in list conversions (see `run/3`)
+    * `:float_atoms` - decode `:nan` and the infinities as floats (see `run/3`)
 
   ## Examples
 
       {:ok, next_progress} = ExMonty.resume(snapshot, {:ok, "response body"})
       {:ok, next_progress} = ExMonty.resume(snapshot, {:error, :runtime_error, "fetch failed"})
   """
-  @spec resume(snapshot(), {:ok, term()} | {:error, atom(), String.t()}) ::
+  @spec resume(
+          snapshot(),
+          {:ok, term()} | {:error, atom(), String.t()} | {:error, ExMonty.Exception.t()},
+          keyword()
+        ) ::
           {:ok, progress()} | {:error, error_reason()}
-  def resume(snapshot, result) do
-    case Native.resume(snapshot, result) do
+  def resume(snapshot, result, opts \\ []) do
+    case Native.resume(snapshot, result, native_opts(opts)) do
       {:error, reason} -> {:error, reason}
       {:ok, progress} -> {:ok, progress}
       progress when is_tuple(progress) -> {:ok, progress}
@@ -245,16 +510,26 @@ defmodule ExMonty do
 
   Each result is a `{call_id, {:ok, value}}` or `{call_id, {:error, type, message}}` tuple.
 
+  ## Options
+
+    * `:print_to` - stream `print()` output while running (see `run/3`)
+    * `:cancel` - a token from `cancel_token/1` (see `run/3`)
+    * `:stats` - append a resource usage map to the progress tuple (see `run/3`)
+    * `:dict_format` - how dicts are returned (see `run/3`)
+    * `:atom_policy` - how atoms in the results are decoded (see `run/3`)
+    * `:keyword_lists`, `:charlists` - opt-in list conversions (see `run/3`)
+    * `:float_atoms` - decode `:nan` and the infinities as floats (see `run/3`)
+
   ## Examples
 
       ids = ExMonty.pending_call_ids(futures)
       results = Enum.map(ids, fn id -> {id, {:ok, compute(id)}} end)
       {:ok, next_progress} = ExMonty.resume_futures(futures, results)
   """
-  @spec resume_futures(future_snapshot(), [{non_neg_integer(), term()}]) ::
+  @spec resume_futures(future_snapshot(), [{non_neg_integer(), term()}], keyword()) ::
           {:ok, progress()} | {:error, error_reason()}
-  def resume_futures(futures, results) do
-    case Native.resume_futures(futures, results) do
+  def resume_futures(futures, results, opts \\ []) do
+    case Native.resume_futures(futures, results, native_opts(opts)) do
       {:error, reason} -> {:error, reason}
       {:ok, progress} -> {:ok, progress}
       progress when is_tuple(progress) -> {:ok, progress}
@@ -267,6 +542,9 @@ defmodule ExMonty do
   @doc """
   Returns the list of pending call IDs from a future snapshot.
 
+  Raises `ErlangError` with an `ExMonty.Error` of kind `:consumed_snapshot`
+  as its `original` if the future snapshot was already resumed or dumped.
+
   ## Examples
 
       ids = ExMonty.pending_call_ids(futures)
@@ -277,6 +555,241 @@ defmodule ExMonty do
     Native.pending_call_ids(futures)
   end
 
+  @doc """
+  Returns what is left of the execution's budget at a snapshot or future snapshot.
+
+  Values are `nil` for limits that were not set. Does not consume the snapshot.
+  Raises like `pending_call_ids/1` if the snapshot was already consumed.
+
+  ## Examples
+
+      {:ok, {:function_call, _call, snapshot, _}} =
+        ExMonty.start(runner, %{}, limits: %{max_duration_secs: 5.0, time_budget: :cpu})
+
+      ExMonty.remaining_budget(snapshot)
+      # %{time_secs: 4.98, allocations: nil, memory: nil, output_bytes: nil}
+  """
+  @spec remaining_budget(snapshot() | future_snapshot()) :: %{
+          time_secs: float() | nil,
+          allocations: non_neg_integer() | nil,
+          memory: non_neg_integer() | nil,
+          output_bytes: non_neg_integer() | nil
+        }
+  def remaining_budget(snapshot) do
+    Native.remaining_budget(snapshot)
+  end
+
+  @doc """
+  Creates a REPL session whose globals persist across `session_feed/3` calls.
+
+  ## Options
+
+    * `:external_functions` - external function names that will pause execution (default: `[]`)
+    * `:script_name` - script name for tracebacks (default: `"main.py"`)
+    * `:limits` - resource limits (see `run/3`); time and output limits apply to
+      each cell separately
+
+  ## Examples
+
+      {:ok, session} = ExMonty.session_new()
+      {:ok, {:complete, nil, ""}} = ExMonty.session_feed(session, "x = 20")
+      {:ok, {:complete, 21, ""}} = ExMonty.session_feed(session, "x + 1")
+  """
+  @spec session_new(keyword()) :: {:ok, session()} | {:error, error_reason()}
+  def session_new(opts \\ []) do
+    external_fns = opts |> Keyword.get(:external_functions, []) |> Enum.map(&to_string/1)
+    script_name = opts |> Keyword.get(:script_name, "main.py") |> to_string()
+    limits = Keyword.get(opts, :limits, nil)
+
+    with :ok <- validate_name_list("external_functions", external_fns) do
+      case Native.session_new(script_name, Enum.sort(external_fns), limits) do
+        {:error, reason} -> {:error, reason}
+        session when is_reference(session) -> {:ok, session}
+      end
+    end
+  end
+
+  @doc """
+  Runs `code` as the next cell of a session.
+
+  Variables, functions and imports defined by earlier cells are available.
+  Returns the same progress values as `start/3`; `{:complete, value, output}`
+  carries the value of the cell's last expression. Paused cells are resumed
+  with `resume/3` and `resume_futures/3` as usual, and the session accepts the
+  next cell once the paused one has completed or failed. An exception fails
+  the cell but keeps the session.
+
+  Only one cell can run at a time: feeding a session while a cell is running
+  or paused returns an `ExMonty.Error` of kind `:session_busy`. To abandon a
+  paused cell, resume it with an error, or drop its snapshot: once the
+  snapshot is garbage collected, the cell is failed with a `RuntimeError`
+  that it cannot catch, and the session accepts the next cell. What the cell
+  did before pausing is kept. A cell that panics loses the session's state:
+  the session starts over with no globals.
+
+  ## Options
+
+    * `:print_to` - stream `print()` output while running (see `run/3`)
+    * `:cancel` - a token from `cancel_token/1` (see `run/3`)
+    * `:stats` - append a resource usage map to the progress tuple (see `run/3`)
+    * `:dict_format` - how dicts are returned (see `run/3`)
+
+  ## Examples
+
+      {:ok, session} = ExMonty.session_new(external_functions: ["fetch"])
+      {:ok, {:function_call, call, snapshot, _}} = ExMonty.session_feed(session, "data = fetch()")
+      {:ok, {:complete, nil, _}} = ExMonty.resume(snapshot, {:ok, [1, 2, 3]})
+      {:ok, {:complete, 3, _}} = ExMonty.session_feed(session, "len(data)")
+  """
+  @spec session_feed(session(), String.t(), keyword()) ::
+          {:ok, progress()} | {:error, error_reason()}
+  def session_feed(session, code, opts \\ []) do
+    case Native.session_feed(session, code, native_opts(opts)) do
+      {:error, reason} -> {:error, reason}
+      progress when is_tuple(progress) -> {:ok, progress}
+    end
+  rescue
+    e in ErlangError ->
+      {:error, e.original}
+  end
+
+  @doc """
+  Like `run/3`, but runs the script on a background thread pool instead of a
+  dirty scheduler.
+
+  Returns `{:ok, ref}` right away. When the script finishes,
+  `{:ex_monty_result, ref, result}` is sent to the calling process, where
+  `result` is what `run/3` would have returned. Use `await/2` to wait for it.
+
+  Invalid limits or options are reported immediately as `{:error, reason}`.
+  Inputs are decoded on the pool, so an input that cannot be passed to Python
+  arrives as an `:input_error` result.
+
+  The pool has one thread per CPU by default; see `set_async_pool_size/1`.
+
+  ## Examples
+
+      {:ok, ref} = ExMonty.run_async(runner, %{"x" => 1})
+      {:ok, result, output} = ExMonty.await(ref)
+  """
+  @spec run_async(runner(), map(), keyword()) :: {:ok, reference()} | {:error, error_reason()}
+  def run_async(runner, inputs \\ %{}, opts \\ []) do
+    limits = Keyword.get(opts, :limits, nil)
+    input_list = Enum.map(inputs, fn {k, v} -> {to_string(k), v} end)
+
+    async(&Native.run_async(runner, input_list, limits, native_opts(opts), &1))
+  end
+
+  @doc """
+  Like `start/3`, but runs on the background thread pool (see `run_async/3`).
+
+  The message carries `{:ok, progress}` or `{:error, reason}`.
+  """
+  @spec start_async(runner(), map(), keyword()) :: {:ok, reference()} | {:error, error_reason()}
+  def start_async(runner, inputs \\ %{}, opts \\ []) do
+    limits = Keyword.get(opts, :limits, nil)
+    input_list = Enum.map(inputs, fn {k, v} -> {to_string(k), v} end)
+
+    async(&Native.start_async(runner, input_list, limits, native_opts(opts), &1))
+  end
+
+  @doc """
+  Like `resume/3`, but runs on the background thread pool (see `run_async/3`).
+
+  An already consumed snapshot is reported immediately. Otherwise the
+  snapshot is consumed, and the result decoded, once the pool picks up the
+  call.
+  """
+  @spec resume_async(snapshot(), {:ok, term()} | {:error, atom(), String.t()}, keyword()) ::
+          {:ok, reference()} | {:error, error_reason()}
+  def resume_async(snapshot, result, opts \\ []) do
+    async(&Native.resume_async(snapshot, result, native_opts(opts), &1))
+  end
+
+  @doc """
+  Like `resume_futures/3`, but runs on the background thread pool (see `run_async/3`).
+  """
+  @spec resume_futures_async(future_snapshot(), [{non_neg_integer(), term()}], keyword()) ::
+          {:ok, reference()} | {:error, error_reason()}
+  def resume_futures_async(futures, results, opts \\ []) do
+    async(&Native.resume_futures_async(futures, results, native_opts(opts), &1))
+  end
+
+  @doc """
+  Waits for the result of an async call started with `run_async/3` and friends.
+
+  Returns `{:error, :timeout}` if no result arrives within `timeout` milliseconds.
+  """
+  @spec await(reference(), timeout()) :: term()
+  def await(ref, timeout \\ :infinity) do
+    receive do
+      {:ex_monty_result, ^ref, result} -> result
+    after
+      timeout -> {:error, :timeout}
+    end
+  end
+
+  @doc """
+  Returns the number of threads in the background pool used by the async functions.
+
+  After the pool is shrunk this is the new size, even while surplus threads
+  finish the work already queued.
+  """
+  @spec async_pool_size() :: pos_integer()
+  def async_pool_size do
+    Native.pool_size()
+  end
+
+  @doc """
+  Sets the number of threads in the background pool, capping how many async
+  executions run at once. Further calls are queued.
+
+  Shrinking takes effect as threads finish the work already queued.
+  """
+  @spec set_async_pool_size(pos_integer()) :: :ok
+  def set_async_pool_size(size) when is_integer(size) and size > 0 do
+    Native.set_pool_size(size)
+  end
+
+  @doc """
+  Creates a cancellation token to pass as the `:cancel` option.
+
+  The token is cancelled automatically when its owner process exits, so
+  scripts run on behalf of a process that has gone away stop on their own.
+
+  ## Options
+
+    * `:owner` - process whose exit cancels the token (default: `self()`);
+      `nil` disables this
+
+  ## Examples
+
+      token = ExMonty.cancel_token()
+      task = Task.async(fn -> ExMonty.run(runner, %{}, cancel: token) end)
+      :ok = ExMonty.cancel(token)
+      {:error, %ExMonty.Error{kind: :cancelled}} = Task.await(task)
+  """
+  @spec cancel_token(keyword()) :: cancel_token()
+  def cancel_token(opts \\ []) do
+    Native.cancel_token_new(Keyword.get(opts, :owner, self()))
+  end
+
+  @doc """
+  Cancels every execution using `token`. Can be called from any process.
+  """
+  @spec cancel(cancel_token()) :: :ok
+  def cancel(token) do
+    Native.cancel(token)
+  end
+
+  @doc """
+  Returns whether `token` has been cancelled.
+  """
+  @spec cancelled?(cancel_token()) :: boolean()
+  def cancelled?(token) do
+    Native.cancelled(token)
+  end
+
   @doc """
   Serializes a runner to a binary for storage or transfer.
 
@@ -286,9 +799,12 @@ defmodule ExMonty do
       {:ok, binary} = ExMonty.dump(runner)
       {:ok, restored} = ExMonty.load_runner(binary)
   """
-  @spec dump(runner()) :: {:ok, binary()} | {:error, term()}
+  @spec dump(runner()) :: {:ok, binary()} | {:error, error_reason()}
   def dump(runner) do
-    {:ok, Native.dump_runner(runner)}
+    case Native.dump_runner(runner) do
+      {:error, reason} -> {:error, reason}
+      result -> {:ok, result}
+    end
   rescue
     e in ErlangError ->
       {:error, e.original}
@@ -301,9 +817,12 @@ defmodule ExMonty do
 
       {:ok, runner} = ExMonty.load_runner(binary)
   """
-  @spec load_runner(binary()) :: {:ok, runner()} | {:error, term()}
+  @spec load_runner(binary()) :: {:ok, runner()} | {:error, error_reason()}
   def load_runner(binary) do
-    {:ok, Native.load_runner(binary)}
+    case Native.load_runner(binary) do
+      {:error, reason} -> {:error, reason}
+      result -> {:ok, result}
+    end
   rescue
     e in ErlangError ->
       {:error, e.original}
@@ -314,9 +833,12 @@ defmodule ExMonty do
 
   Note: This consumes the snapshot — it cannot be used for resumption after dumping.
   """
-  @spec dump_snapshot(snapshot()) :: {:ok, binary()} | {:error, term()}
+  @spec dump_snapshot(snapshot()) :: {:ok, binary()} | {:error, error_reason()}
   def dump_snapshot(snapshot) do
-    {:ok, Native.dump_snapshot(snapshot)}
+    case Native.dump_snapshot(snapshot) do
+      {:error, reason} -> {:error, reason}
+      result -> {:ok, result}
+    end
   rescue
     e in ErlangError ->
       {:error, e.original}
@@ -325,9 +847,12 @@ defmodule ExMonty do
   @doc """
   Deserializes a snapshot from a binary.
   """
-  @spec load_snapshot(binary()) :: {:ok, snapshot()} | {:error, term()}
+  @spec load_snapshot(binary()) :: {:ok, snapshot()} | {:error, error_reason()}
   def load_snapshot(binary) do
-    {:ok, Native.load_snapshot(binary)}
+    case Native.load_snapshot(binary) do
+      {:error, reason} -> {:error, reason}
+      result -> {:ok, result}
+    end
   rescue
     e in ErlangError ->
       {:error, e.original}
@@ -338,9 +863,12 @@ defmodule ExMonty do
 
   Note: This consumes the future snapshot.
   """
-  @spec dump_future_snapshot(future_snapshot()) :: {:ok, binary()} | {:error, term()}
+  @spec dump_future_snapshot(future_snapshot()) :: {:ok, binary()} | {:error, error_reason()}
   def dump_future_snapshot(futures) do
-    {:ok, Native.dump_future_snapshot(futures)}
+    case Native.dump_future_snapshot(futures) do
+      {:error, reason} -> {:error, reason}
+      result -> {:ok, result}
+    end
   rescue
     e in ErlangError ->
       {:error, e.original}
@@ -349,20 +877,104 @@ defmodule ExMonty do
   @doc """
   Deserializes a future snapshot from a binary.
   """
-  @spec load_future_snapshot(binary()) :: {:ok, future_snapshot()} | {:error, term()}
+  @spec load_future_snapshot(binary()) :: {:ok, future_snapshot()} | {:error, error_reason()}
   def load_future_snapshot(binary) do
-    {:ok, Native.load_future_snapshot(binary)}
+    case Native.load_future_snapshot(binary) do
+      {:error, reason} -> {:error, reason}
+      result -> {:ok, result}
+    end
+  rescue
+    e in ErlangError ->
+      {:error, e.original}
+  end
+
+  @doc """
+  Serializes an idle session, including its globals, to a binary.
+
+  Unlike snapshots, the session stays usable. Returns an error while a cell
+  is running or paused.
+  """
+  @spec dump_session(session()) :: {:ok, binary()} | {:error, error_reason()}
+  def dump_session(session) do
+    case Native.dump_session(session) do
+      {:error, reason} -> {:error, reason}
+      result -> {:ok, result}
+    end
+  rescue
+    e in ErlangError ->
+      {:error, e.original}
+  end
+
+  @doc """
+  Deserializes a session from a binary.
+  """
+  @spec load_session(binary()) :: {:ok, session()} | {:error, error_reason()}
+  def load_session(binary) do
+    case Native.load_session(binary) do
+      {:error, reason} -> {:error, reason}
+      result -> {:ok, result}
+    end
   rescue
     e in ErlangError ->
       {:error, e.original}
   end
 
+  # Items that are not input maps are passed on as they are, so that they
+  # fail on their own in the NIF instead of failing the whole batch here.
+  defp batch_inputs(inputs) when is_map(inputs), do: Enum.map(inputs, &batch_input/1)
+
+  defp batch_inputs(inputs) when is_list(inputs) do
+    if pair_list?(inputs), do: Enum.map(inputs, &batch_input/1), else: inputs
+  end
+
+  defp batch_inputs(inputs), do: inputs
+
+  defp pair_list?([{_, _} | rest]), do: pair_list?(rest)
+  defp pair_list?([]), do: true
+  defp pair_list?(_), do: false
+
+  defp batch_input({name, value}) when is_binary(name) or is_atom(name) or is_number(name),
+    do: {to_string(name), value}
+
+  defp batch_input(other), do: other
+
+  defp async(call) do
+    ref = make_ref()
+
+    case call.(ref) do
+      :ok -> {:ok, ref}
+      {:error, reason} -> {:error, reason}
+    end
+  rescue
+    e in ErlangError ->
+      {:error, e.original}
+  end
+
+  defp native_opts(opts) do
+    %{
+      print_to: normalize_print_to(Keyword.get(opts, :print_to)),
+      cancel: Keyword.get(opts, :cancel),
+      stats: Keyword.get(opts, :stats, false),
+      dict_format: Keyword.get(opts, :dict_format),
+      atom_policy: Keyword.get(opts, :atom_policy),
+      keyword_lists: Keyword.get(opts, :keyword_lists),
+      charlists: Keyword.get(opts, :charlists),
+      float_atoms: Keyword.get(opts, :float_atoms)
+    }
+  end
+
+  defp normalize_print_to(nil), do: nil
+  defp normalize_print_to(pid) when is_pid(pid), do: {pid, nil}
+  defp normalize_print_to({pid, _ref} = target) when is_pid(pid), do: target
+  # Anything else is rejected by the NIF as an invalid option.
+  defp normalize_print_to(other), do: other
+
   defp validate_name_list(_label, []), do: :ok
 
   defp validate_name_list(label, names) when is_list(names) do
     cond do
       Enum.any?(names, &(&1 == "")) ->
-        {:error, "#{label} must not contain empty strings"}
+        {:error, invalid_option(label, "#{label} must not contain empty strings")}
 
       true ->
         duplicates =
@@ -375,8 +987,16 @@ defmodule ExMonty do
         if duplicates == [] do
           :ok
         else
-          {:error, "duplicate #{label}: #{Enum.join(duplicates, ", ")}"}
+          {:error, invalid_option(label, "duplicate #{label}: #{Enum.join(duplicates, ", ")}")}
         end
     end
   end
+
+  defp invalid_option(label, message) do
+    %ExMonty.Error{
+      kind: :invalid_option,
+      message: message,
+      details: %{option: String.to_existing_atom(label)}
+    }
+  end
 end
@@ -2,18 +2,27 @@ defmodule ExMonty.Dataclass do
   @moduledoc """
   Represents a Python dataclass instance.
 
+  Passing the struct back into Python (as an input or a handler result)
+  rebuilds the dataclass, so its fields can be read with attribute syntax.
+
   ## Fields
 
     * `:name` - the dataclass type name
+    * `:type_id` - identifies the class inside the interpreter; `nil` for
+      structs built in Elixir, which are not instances of any Python class
     * `:fields` - map of field name strings to values
+    * `:field_names` - field names in declaration order; when empty, the
+      fields are ordered as in the `:fields` map
     * `:frozen` - whether the dataclass is frozen (immutable)
   """
 
   @type t :: %__MODULE__{
           name: String.t(),
+          type_id: non_neg_integer() | nil,
           fields: map(),
+          field_names: [String.t()],
           frozen: boolean()
         }
 
-  defstruct [:name, :fields, frozen: false]
+  defstruct [:name, :fields, type_id: nil, field_names: [], frozen: false]
 end
@@ -0,0 +1,74 @@
+defmodule ExMonty.Error do
+  @moduledoc """
+  Represents a failure other than a Python exception: bad inputs or
+  options, a consumed snapshot, a cancelled script, and so on.
+
+  Every function in `ExMonty` fails with `{:error, reason}`, where `reason`
+  is either this struct or an `ExMonty.Exception`. Both have a `:kind`, so
+  callers can branch on `%{kind: kind}` without caring which struct it is.
+
+  ## Fields
+
+    * `:kind` - what went wrong, as an atom (see below)
+    * `:message` - a human-readable description
+    * `:details` - a map of machine-readable fields, depending on `:kind`
+
+  ## Kinds
+
+  Returned as `%ExMonty.Error{}`:
+
+    * `:input_error` - an input or handler result cannot be passed to
+      Python. Details: `:path` (such as `"x[0][\\"key\\"]"`) for a value that
+      does not decode; `:missing`, `:unexpected` or `:duplicate` (lists of
+      input names) for inputs that do not match the runner
+    * `:invalid_option` - an option or limit is malformed. Details:
+      `:option`, the option or limit key at fault (such as `:max_memory`)
+    * `:consumed_snapshot` - the snapshot or future snapshot was already
+      resumed or dumped
+    * `:session_busy` - the session has a cell running or paused
+    * `:invalid_snapshot` - a binary passed to a `load_*` function is not a
+      dump of the expected kind
+    * `:unsupported_snapshot_version` - a binary passed to a `load_*`
+      function is a dump from an incompatible version of ExMonty. Details:
+      `:version`, the dump's format version (0 for dumps written before
+      dumps were versioned)
+    * `:serialization_error` - a `dump*` function could not serialize
+    * `:out_of_memory` - a binary could not be allocated
+    * `:cancelled` - the script was stopped through its cancel token
+    * `:internal_error` - the NIF or the interpreter panicked. The message
+      starts with `"panic: "`. The calling process is unaffected, but the
+      snapshot involved may have been consumed, and a session starts over
+      with no globals.
+
+  Returned as `%ExMonty.Exception{}`:
+
+    * `:python_exception` - the script raised an exception
+    * `:limit_exceeded` - a resource limit stopped the script; the
+      exception's `:limit` says which
+    * `:compile_error` - the code does not compile; the exception's
+      `:diagnostics` say where
+
+  `ExMonty.await/2` returns `{:error, :timeout}` when no result arrives in
+  time; that is a property of the wait, not of the execution.
+  """
+
+  @type kind ::
+          :input_error
+          | :invalid_option
+          | :consumed_snapshot
+          | :session_busy
+          | :invalid_snapshot
+          | :unsupported_snapshot_version
+          | :serialization_error
+          | :out_of_memory
+          | :cancelled
+          | :internal_error
+
+  @type t :: %__MODULE__{
+          kind: kind(),
+          message: String.t(),
+          details: map()
+        }
+
+  defstruct [:kind, :message, details: %{}]
+end
@@ -4,18 +4,141 @@ defmodule ExMonty.Exception do
 
   ## Fields
 
+    * `:kind` - `:python_exception`, `:limit_exceeded` when a resource limit
+      stopped the script, or `:compile_error` (see `ExMonty.Error` for the
+      full list of error kinds)
     * `:type` - the exception type as an atom (e.g., `:value_error`, `:type_error`)
     * `:message` - the exception message string, or `nil`
+    * `:args` - the exception arguments; `[message]`, or `[]` when there is
+      no message
     * `:traceback` - list of `ExMonty.StackFrame` structs
+    * `:limit` - the resource limit that stopped execution, or `nil` if the
+      exception was raised by the script itself
+    * `:diagnostics` - for errors from `ExMonty.compile/2`, a list of
+      `ExMonty.Diagnostic` structs locating the problem; `[]` otherwise
+
+  The same struct represents exception objects returned as values (e.g. a
+  list of caught errors). Those have an empty `:traceback`, since Python
+  exception values do not carry one here, and passing the struct back into
+  Python (as an input or a handler result) rebuilds the exception object
+  from `:type` and `:message`.
+
+  ## Raising From Handlers
+
+  A handler can return `{:error, %ExMonty.Exception{}}` to raise the
+  exception in Python. `:type` must name a built-in exception (an unknown
+  name is an `:input_error`, not a `RuntimeError`). When `:message` is `nil`,
+  the message is `str()` of the single argument in `:args`:
+
+      {:error, %ExMonty.Exception{type: :value_error, args: [42]}}
+      # raises ValueError with message "42"
+
+  The argument must be a string, number, boolean or `nil`. The interpreter's
+  exceptions only have a type and a message, so more than one argument is an
+  `:input_error` rather than being joined into a message, and causes, notes
+  and exception classes defined in the script cannot be raised from a
+  handler.
+
+  ## Limits
+
+  When a resource limit is exceeded, `:limit` holds a tuple describing which
+  limit was hit and by how much:
+
+    * `{:time_limit, limit_secs, elapsed_secs}`
+    * `{:memory_limit, limit_bytes, used_bytes}`
+    * `{:allocation_limit, limit, count}`
+    * `{:recursion_limit, limit, depth}`
+    * `{:output_limit, limit_bytes, written_bytes}`
   """
 
+  @type limit ::
+          {:time_limit, float(), float()}
+          | {:memory_limit, non_neg_integer(), non_neg_integer()}
+          | {:allocation_limit, non_neg_integer(), non_neg_integer()}
+          | {:recursion_limit, non_neg_integer(), non_neg_integer()}
+          | {:output_limit, non_neg_integer(), non_neg_integer()}
+
+  @type kind :: :python_exception | :limit_exceeded | :compile_error
+
   @type t :: %__MODULE__{
+          kind: kind(),
           type: atom(),
           message: String.t() | nil,
-          traceback: [ExMonty.StackFrame.t()]
+          args: [term()],
+          traceback: [ExMonty.StackFrame.t()],
+          limit: limit() | nil,
+          diagnostics: [ExMonty.Diagnostic.t()]
         }
 
-  defstruct [:type, :message, traceback: []]
+  defstruct [
+    :type,
+    :message,
+    :limit,
+    kind: :python_exception,
+    args: [],
+    traceback: [],
+    diagnostics: []
+  ]
+
+  @doc """
+  Formats the exception the way CPython prints an uncaught exception.
+
+  Frames with a `:source` line show it, with carets under the span that
+  raised unless the span covers the whole line:
+
+      Traceback (most recent call last):
+        File "main.py", line 2, in <module>
+          x = 1 / 0
+              ^^^^^
+      ZeroDivisionError: division by zero
+
+  Exceptions without a traceback (such as exception values) format as the
+  last line only.
+  """
+  @spec format(t()) :: String.t()
+  def format(%__MODULE__{} = exception) do
+    traceback =
+      case exception.traceback do
+        [] -> []
+        frames -> ["Traceback (most recent call last):\n" | Enum.map(frames, &format_frame/1)]
+      end
+
+    IO.iodata_to_binary([traceback, format_exception_only(exception)])
+  end
+
+  defp format_frame(%ExMonty.StackFrame{} = frame) do
+    header = ~s(  File "#{frame.filename}", line #{frame.line}, in #{frame.name || "<module>"}\n)
+    [header | format_source(frame)]
+  end
+
+  defp format_source(%ExMonty.StackFrame{source: source, caret: caret}) when is_binary(source) do
+    trimmed = String.trim_leading(source)
+    indent = String.length(source) - String.length(trimmed)
+    code = String.trim_trailing(trimmed)
+
+    line = ["    ", code, "\n"]
+
+    with {first, last} <- caret,
+         offset = max(first - 1 - indent, 0),
+         width = min(last - indent, String.length(code)) - offset,
+         true <- width > 0 and width < String.length(code) do
+      [line, "    ", String.duplicate(" ", offset), String.duplicate("^", width), "\n"]
+    else
+      _ -> line
+    end
+  end
+
+  defp format_source(_frame), do: []
+
+  defp format_exception_only(%__MODULE__{type: type, message: message}) do
+    name = type |> to_string() |> Macro.camelize()
+
+    case message do
+      nil -> [name, "\n"]
+      "" -> [name, "\n"]
+      message -> [name, ": ", message, "\n"]
+    end
+  end
 end
 
 defmodule ExMonty.StackFrame do
@@ -30,6 +153,10 @@ defmodule ExMonty.StackFrame do
     * `:end_line` - end line number
     * `:end_column` - end column number
     * `:name` - function/frame name, or `nil` for module-level code
+    * `:source` - the source line the frame starts on, or `nil` if the
+      interpreter has none
+    * `:caret` - `{first, last}` columns (1-based, inclusive) of the span
+      within `:source`, cut off at the end of the line, or `nil`
   """
 
   @type t :: %__MODULE__{
@@ -38,8 +165,39 @@ defmodule ExMonty.StackFrame do
           column: non_neg_integer(),
           end_line: non_neg_integer(),
           end_column: non_neg_integer(),
-          name: String.t() | nil
+          name: String.t() | nil,
+          source: String.t() | nil,
+          caret: {pos_integer(), pos_integer()} | nil
+        }
+
+  defstruct [:filename, :line, :column, :end_line, :end_column, :name, :source, :caret]
+end
+
+defmodule ExMonty.Diagnostic do
+  @moduledoc """
+  A problem found while compiling a script, located precisely enough for an
+  editor to underline it.
+
+  ## Fields
+
+    * `:line` - start line number (1-based)
+    * `:column` - start column number (1-based)
+    * `:end_line` - end line number
+    * `:end_column` - end column number
+    * `:message` - what is wrong, such as `"Expected an expression"`
+
+  The location fields are `nil` when the interpreter reports no position.
+  The parser stops at the first error, so a compile error currently carries
+  a single diagnostic.
+  """
+
+  @type t :: %__MODULE__{
+          line: pos_integer() | nil,
+          column: pos_integer() | nil,
+          end_line: pos_integer() | nil,
+          end_column: pos_integer() | nil,
+          message: String.t()
         }
 
-  defstruct [:filename, :line, :column, :end_line, :end_column, :name]
+  defstruct [:line, :column, :end_line, :end_column, message: ""]
 end
@@ -9,14 +9,36 @@ defmodule ExMonty.Native do
   def compile(_code, _script_name, _input_names, _external_fns),
     do: :erlang.nif_error(:nif_not_loaded)
 
-  def run(_runner, _inputs, _limits), do: :erlang.nif_error(:nif_not_loaded)
+  def run(_runner, _inputs, _limits, _opts), do: :erlang.nif_error(:nif_not_loaded)
   def run_no_limits(_runner, _inputs), do: :erlang.nif_error(:nif_not_loaded)
+  def run_batch(_runner, _batch, _limits, _opts), do: :erlang.nif_error(:nif_not_loaded)
 
   # Interactive
-  def start(_runner, _inputs, _limits), do: :erlang.nif_error(:nif_not_loaded)
-  def resume(_snapshot, _result), do: :erlang.nif_error(:nif_not_loaded)
-  def resume_futures(_futures, _results), do: :erlang.nif_error(:nif_not_loaded)
+  def start(_runner, _inputs, _limits, _opts), do: :erlang.nif_error(:nif_not_loaded)
+  def resume(_snapshot, _result, _opts), do: :erlang.nif_error(:nif_not_loaded)
+  def resume_futures(_futures, _results, _opts), do: :erlang.nif_error(:nif_not_loaded)
   def pending_call_ids(_futures), do: :erlang.nif_error(:nif_not_loaded)
+  def remaining_budget(_snapshot), do: :erlang.nif_error(:nif_not_loaded)
+
+  # Sessions
+  def session_new(_script_name, _external_fns, _limits), do: :erlang.nif_error(:nif_not_loaded)
+  def session_feed(_session, _code, _opts), do: :erlang.nif_error(:nif_not_loaded)
+
+  # Async
+  def run_async(_runner, _inputs, _limits, _opts, _ref), do: :erlang.nif_error(:nif_not_loaded)
+  def start_async(_runner, _inputs, _limits, _opts, _ref), do: :erlang.nif_error(:nif_not_loaded)
+  def resume_async(_snapshot, _result, _opts, _ref), do: :erlang.nif_error(:nif_not_loaded)
+
+  def resume_futures_async(_futures, _results, _opts, _ref),
+    do: :erlang.nif_error(:nif_not_loaded)
+
+  def pool_size, do: :erlang.nif_error(:nif_not_loaded)
+  def set_pool_size(_size), do: :erlang.nif_error(:nif_not_loaded)
+
+  # Cancellation
+  def cancel_token_new(_owner), do: :erlang.nif_error(:nif_not_loaded)
+  def cancel(_token), do: :erlang.nif_error(:nif_not_loaded)
+  def cancelled(_token), do: :erlang.nif_error(:nif_not_loaded)
 
   # Serialization
   def dump_runner(_runner), do: :erlang.nif_error(:nif_not_loaded)
@@ -25,4 +47,6 @@ defmodule ExMonty.Native do
   def load_snapshot(_binary), do: :erlang.nif_error(:nif_not_loaded)
   def dump_future_snapshot(_futures), do: :erlang.nif_error(:nif_not_loaded)
   def load_future_snapshot(_binary), do: :erlang.nif_error(:nif_not_loaded)
+  def dump_session(_session), do: :erlang.nif_error(:nif_not_loaded)
+  def load_session(_binary), do: :erlang.nif_error(:nif_not_loaded)
 end
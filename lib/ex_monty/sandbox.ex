@@ -56,12 +56,26 @@ defmodule ExMonty.Sandbox do
       )
   """
 
-  @type handler_result :: {:ok, term()} | {:error, atom(), String.t()}
+  # Options passed through to every start/resume step.
+  @step_opts [
+    :print_to,
+    :cancel,
+    :dict_format,
+    :atom_policy,
+    :keyword_lists,
+    :charlists,
+    :float_atoms
+  ]
+
+  @type handler_result ::
+          {:ok, term()} | {:error, atom(), String.t()} | {:error, ExMonty.Exception.t()}
 
   @doc """
   Called when Python code invokes an external function.
 
   Should return `{:ok, value}` on success or `{:error, exc_type, message}` on failure.
+  `{:error, %ExMonty.Exception{}}` raises an exception with `args` instead of
+  a message.
   """
   @callback handle_function(name :: String.t(), args :: list(), kwargs :: map()) ::
               handler_result()
@@ -87,6 +101,16 @@ defmodule ExMonty.Sandbox do
       * An `ExMonty.PseudoFS` struct for in-memory filesystem
       * A map of `%{atom => fn args, kwargs -> result}` for per-function handlers
     * `:limits` - resource limits map (default: `nil`)
+    * `:print_to` - stream `print()` output while running (see `ExMonty.run/3`)
+    * `:dict_format` - how dicts are returned (see `ExMonty.run/3`)
+    * `:atom_policy` - how atoms in inputs and handler results are decoded
+      (see `ExMonty.run/3`)
+    * `:keyword_lists`, `:charlists` - decode keyword lists as dicts and
+      charlists as strings (see `ExMonty.run/3`)
+    * `:float_atoms` - decode `:nan`, `:infinity` and `:neg_infinity` as
+      floats (see `ExMonty.run/3`)
+    * `:cancel` - a token from `ExMonty.cancel_token/1` that stops the script;
+      fails with an `ExMonty.Error` of kind `:cancelled`
     * `:external_functions` - list of external function names (auto-detected from `:functions`)
     * `:script_name` - script name for tracebacks (default: `"main.py"`)
 
@@ -124,6 +148,7 @@ defmodule ExMonty.Sandbox do
     functions = opts |> Keyword.get(:functions, %{}) |> normalize_function_handlers()
     os_handlers = opts |> Keyword.get(:os, %{}) |> normalize_os_handlers()
     limits = Keyword.get(opts, :limits, nil)
+    step_opts = Keyword.take(opts, @step_opts)
     script_name = Keyword.get(opts, :script_name, "main.py")
 
     external_fns =
@@ -142,24 +167,60 @@ defmodule ExMonty.Sandbox do
     ]
 
     with {:ok, runner} <- ExMonty.compile(code, compile_opts),
-         {:ok, progress} <- ExMonty.start(runner, inputs, limits: limits) do
+         {:ok, progress} <- ExMonty.start(runner, inputs, [limits: limits] ++ step_opts) do
       state = %{
         handler: handler,
         functions: functions,
-        os: os_handlers
+        os: os_handlers,
+        step_opts: step_opts
       }
 
       loop(progress, state, "")
     end
   end
 
+  @doc """
+  Runs `code` as the next cell of a session created with `ExMonty.session_new/1`,
+  dispatching its external function and OS calls like `run/2`.
+
+  The session must have been created with the external function names the
+  cell calls. Accepts the `:handler`, `:functions`, `:os`, `:print_to`,
+  `:cancel`, `:dict_format`, `:atom_policy`, `:keyword_lists`, `:charlists`
+  and `:float_atoms` options of `run/2`. Changes a cell makes to an
+  `ExMonty.PseudoFS` are not carried over to the next cell.
+
+  ## Examples
+
+      {:ok, session} = ExMonty.session_new(external_functions: ["double"])
+      functions = %{"double" => fn [x], _kwargs -> {:ok, x * 2} end}
+
+      {:ok, nil, ""} = ExMonty.Sandbox.feed(session, "x = double(21)", functions: functions)
+      {:ok, 42, ""} = ExMonty.Sandbox.feed(session, "x", functions: functions)
+  """
+  @spec feed(ExMonty.session(), String.t(), keyword()) ::
+          {:ok, term(), String.t()} | {:error, term()}
+  def feed(session, code, opts \\ []) do
+    step_opts = Keyword.take(opts, @step_opts)
+
+    state = %{
+      handler: Keyword.get(opts, :handler),
+      functions: opts |> Keyword.get(:functions, %{}) |> normalize_function_handlers(),
+      os: opts |> Keyword.get(:os, %{}) |> normalize_os_handlers(),
+      step_opts: step_opts
+    }
+
+    with {:ok, progress} <- ExMonty.session_feed(session, code, step_opts) do
+      loop(progress, state, "")
+    end
+  end
+
   defp loop(progress, state, acc_output) do
     case progress do
       {:function_call, %ExMonty.FunctionCall{} = call, snapshot, output} ->
         acc_output = acc_output <> output
         result = dispatch_function(call.name, call.args, call.kwargs, state)
 
-        case ExMonty.resume(snapshot, result) do
+        case ExMonty.resume(snapshot, result, state.step_opts) do
           {:ok, next_progress} ->
             loop(next_progress, state, acc_output)
 
@@ -171,7 +232,7 @@ defmodule ExMonty.Sandbox do
         acc_output = acc_output <> output
         {state, result} = dispatch_os(call.function, call.args, call.kwargs, state)
 
-        case ExMonty.resume(snapshot, result) do
+        case ExMonty.resume(snapshot, result, state.step_opts) do
           {:ok, next_progress} ->
             loop(next_progress, state, acc_output)
 
@@ -188,7 +249,7 @@ defmodule ExMonty.Sandbox do
             {id, {:ok, nil}}
           end)
 
-        case ExMonty.resume_futures(futures, results) do
+        case ExMonty.resume_futures(futures, results, state.step_opts) do
           {:ok, next_progress} ->
             loop(next_progress, state, acc_output)
 
@@ -271,6 +332,8 @@ defmodule ExMonty.Sandbox do
 
   defp normalize_handler_result({:ok, _} = ok), do: ok
 
+  defp normalize_handler_result({:error, %ExMonty.Exception{}} = error), do: error
+
   defp normalize_handler_result({:error, type, message}) when is_atom(type) do
     {:error, type, to_string(message)}
   end
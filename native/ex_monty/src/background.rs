@@ -0,0 +1,194 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+
+use rustler::env::{OwnedEnv, SavedTerm};
+use rustler::types::atom::Atom;
+use rustler::{Encoder, Env, LocalPid, NifResult, Term};
+
+use crate::error;
+use crate::resources;
+use crate::step::StepOpts;
+use crate::terms;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+enum Message {
+    Run(Job),
+    Stop,
+}
+
+/// Fixed-size pool of threads that run async executions, so that long
+/// scripts do not occupy the VM's dirty schedulers.
+struct Pool {
+    sender: Sender<Message>,
+    receiver: Arc<Mutex<Receiver<Message>>>,
+    workers: Arc<Mutex<Workers>>,
+}
+
+/// Worker counts. `live` only drops when a worker actually exits, so a
+/// `Stop` left over from an earlier shrink cannot take the pool below
+/// `target` after it has grown again.
+#[derive(Default)]
+struct Workers {
+    live: usize,
+    target: usize,
+}
+
+impl Pool {
+    fn new(size: usize) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        let pool = Self {
+            sender,
+            receiver: Arc::new(Mutex::new(receiver)),
+            workers: Arc::default(),
+        };
+        pool.resize(size);
+        pool
+    }
+
+    /// Grow the pool right away; shrinking stops workers as they finish the
+    /// jobs already queued.
+    fn resize(&self, new_size: usize) {
+        let mut workers = resources::lock(&self.workers);
+        workers.target = new_size;
+        for _ in workers.live..new_size {
+            let receiver = self.receiver.clone();
+            let counts = self.workers.clone();
+            thread::spawn(move || worker(receiver, counts));
+        }
+        for _ in new_size..workers.live {
+            let _ = self.sender.send(Message::Stop);
+        }
+        workers.live = workers.live.max(new_size);
+    }
+}
+
+fn worker(receiver: Arc<Mutex<Receiver<Message>>>, workers: Arc<Mutex<Workers>>) {
+    loop {
+        let message = resources::lock(&receiver).recv();
+        match message {
+            // A panicking job must not take the worker down with it.
+            Ok(Message::Run(job)) => {
+                let _ = panic::catch_unwind(AssertUnwindSafe(job));
+            }
+            // Stops outnumber the surplus once the pool has grown again;
+            // the extra ones are ignored.
+            Ok(Message::Stop) => {
+                let mut workers = resources::lock(&workers);
+                if workers.live > workers.target {
+                    workers.live -= 1;
+                    return;
+                }
+            }
+            Err(_) => return,
+        }
+    }
+}
+
+static POOL: OnceLock<Pool> = OnceLock::new();
+
+fn pool() -> &'static Pool {
+    POOL.get_or_init(|| {
+        let size = thread::available_parallelism().map_or(1, |n| n.get());
+        Pool::new(size)
+    })
+}
+
+#[rustler::nif]
+fn pool_size() -> NifResult<usize> {
+    error::guard(|| Ok(resources::lock(&pool().workers).target))
+}
+
+#[rustler::nif]
+fn set_pool_size(size: usize) -> NifResult<Atom> {
+    error::guard(|| {
+        if size == 0 {
+            return Err(error::Error::invalid_option(
+                "pool_size",
+                "pool size must be a positive integer",
+            )
+            .into());
+        }
+        pool().resize(size);
+        Ok(rustler::types::atom::ok())
+    })
+}
+
+/// The caller of an async NIF, to be sent `{:ex_monty_result, ref, result}`
+/// once the step has run on the pool.
+pub struct Reply {
+    caller: LocalPid,
+    env: OwnedEnv,
+    opts: SavedTerm,
+    reference: SavedTerm,
+}
+
+impl Reply {
+    /// Validate `opts` now, so bad options fail the call itself rather than
+    /// arriving as a message later.
+    pub fn new<'a>(env: Env<'a>, opts: Term<'a>, reference: Term<'a>) -> NifResult<Self> {
+        StepOpts::decode(env, opts)?;
+
+        let owned = OwnedEnv::new();
+        let opts = owned.save(opts);
+        let reference = owned.save(reference);
+        Ok(Self {
+            caller: env.pid(),
+            env: owned,
+            opts,
+            reference,
+        })
+    }
+
+    /// Keep `term` for the job, to be loaded from the job's env. Lets the
+    /// job decode arguments on the pool rather than on the caller's scheduler.
+    pub fn save(&self, term: Term) -> SavedTerm {
+        self.env.save(term)
+    }
+
+    /// Queue `step` on the pool. Its result is sent to the caller as is on
+    /// success, or as `{:error, reason}`.
+    pub fn spawn<F>(self, step: F) -> Atom
+    where
+        F: for<'a> FnOnce(Env<'a>, StepOpts<'a>) -> NifResult<Term<'a>> + Send + 'static,
+    {
+        let job = move || {
+            let Reply {
+                caller,
+                env: owned,
+                opts,
+                reference,
+            } = self;
+
+            owned.run(|env| {
+                let result = error::guard(|| {
+                    StepOpts::decode(env, opts.load(env)).and_then(|opts| step(env, opts))
+                });
+                let result = match result {
+                    Ok(term) => term,
+                    Err(err) => error::encode_error_tuple(env, err),
+                };
+                let message = rustler::types::tuple::make_tuple(
+                    env,
+                    &[
+                        terms::atom(env, "ex_monty_result"),
+                        reference.load(env),
+                        result,
+                    ],
+                );
+                // The caller may have exited in the meantime.
+                let _ = env.send(&caller, message);
+            });
+        };
+
+        let _ = pool().sender.send(Message::Run(Box::new(job)));
+        rustler::types::atom::ok()
+    }
+}
+
+/// Wrap a successful result as `{:ok, term}`.
+pub fn ok<'a>(env: Env<'a>, term: Term<'a>) -> Term<'a> {
+    rustler::types::tuple::make_tuple(env, &[rustler::types::atom::ok().encode(env), term])
+}
@@ -0,0 +1,55 @@
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
+use rustler::{Env, LocalPid, NifResult, ResourceArc, Term};
+
+use crate::error;
+use crate::resources::CancelTokenResource;
+use crate::types;
+
+/// Create a cancellation token. With an `owner`, the token is cancelled
+/// automatically when that process exits.
+#[rustler::nif]
+fn cancel_token_new(
+    env: Env,
+    owner: Option<LocalPid>,
+) -> NifResult<ResourceArc<CancelTokenResource>> {
+    error::guard(|| {
+        let token = ResourceArc::new(CancelTokenResource::default());
+        if let Some(pid) = owner {
+            // `None` means the owner is already dead.
+            if token.monitor(Some(env), &pid).is_none() {
+                token.cancel();
+            }
+        }
+        Ok(token)
+    })
+}
+
+#[rustler::nif]
+fn cancel(token: ResourceArc<CancelTokenResource>) -> NifResult<rustler::Atom> {
+    error::guard(|| {
+        token.cancel();
+        Ok(rustler::types::atom::ok())
+    })
+}
+
+#[rustler::nif]
+fn cancelled(token: ResourceArc<CancelTokenResource>) -> NifResult<bool> {
+    error::guard(|| Ok(token.is_cancelled()))
+}
+
+/// Decode the `:cancel` option: `nil` or a token from `cancel_token_new/1`.
+pub fn decode_cancel(opts: Term) -> NifResult<Option<Arc<AtomicBool>>> {
+    types::get_option(opts, "cancel")?
+        .map(|term| {
+            let token: ResourceArc<CancelTokenResource> = term.decode().map_err(|_| {
+                error::Error::invalid_option(
+                    "cancel",
+                    format!("invalid cancel: expected a cancel token, got: {term:?}"),
+                )
+            })?;
+            Ok(token.flag())
+        })
+        .transpose()
+}
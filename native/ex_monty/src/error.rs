@@ -1,45 +1,273 @@
-use monty::{MontyException, ResourceError};
-use rustler::{Encoder, Env, Term};
+use std::panic::{self, AssertUnwindSafe};
+
+use monty::{ExcType, MontyException, ResourceError};
+use rustler::{Encoder, Env, NifResult, Term};
+
+use crate::print::OutputBudget;
+use crate::terms;
+use crate::tracker::StepReport;
+
+/// An error reported to Elixir as `%ExMonty.Error{kind, message, details}`,
+/// for failures other than a Python exception. `kind` is one of the atoms
+/// documented in `ExMonty.Error`; `details` holds its machine-readable fields.
+#[derive(Debug)]
+pub struct Error {
+    pub kind: &'static str,
+    pub message: String,
+    details: Vec<(&'static str, Detail)>,
+}
+
+/// A value in the `details` map of an error.
+#[derive(Debug)]
+pub enum Detail {
+    Atom(String),
+    Integer(u64),
+    String(String),
+    Strings(Vec<String>),
+}
+
+impl Error {
+    pub fn new(kind: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+            details: Vec::new(),
+        }
+    }
+
+    /// Add `key` to the error's details.
+    pub fn detail(mut self, key: &'static str, value: Detail) -> Self {
+        self.details.push((key, value));
+        self
+    }
+
+    /// A snapshot, future snapshot or session cell that was already resumed
+    /// or dumped; `what` names it in the message.
+    pub fn consumed_snapshot(what: &str) -> Self {
+        Self::new("consumed_snapshot", format!("{what} already consumed"))
+    }
+
+    /// A session fed or dumped while one of its cells is running or paused.
+    pub fn session_busy() -> Self {
+        Self::new("session_busy", "session is busy")
+    }
+
+    /// A malformed option; `option` is the option or limit key at fault.
+    pub fn invalid_option(option: &str, message: impl Into<String>) -> Self {
+        Self::new("invalid_option", message).detail("option", Detail::Atom(option.to_owned()))
+    }
+
+    /// A value passed into Python that cannot be decoded. `path` locates it,
+    /// as in `x[0]["key"]`.
+    pub fn input_error(message: &str, path: String) -> Self {
+        Self::new("input_error", format!("{message} at {path}"))
+            .detail("path", Detail::String(path))
+    }
+
+    /// Raise the error instead of returning it, for NIFs whose Elixir
+    /// functions return a bare value rather than `{:ok, value}`.
+    pub fn raise(self) -> rustler::Error {
+        rustler::Error::RaiseTerm(Box::new(self))
+    }
+}
+
+impl From<Error> for rustler::Error {
+    fn from(err: Error) -> Self {
+        rustler::Error::Term(Box::new(err))
+    }
+}
+
+impl Encoder for Error {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        let details = self.details.iter().map(|(key, value)| {
+            let value = match value {
+                Detail::Atom(name) => terms::atom(env, name),
+                Detail::Integer(n) => n.encode(env),
+                Detail::String(s) => s.encode(env),
+                Detail::Strings(list) => list.encode(env),
+            };
+            (terms::atom(env, key), value)
+        });
+
+        terms::make_struct(
+            env,
+            "ExMonty.Error",
+            &[
+                ("kind", terms::atom(env, self.kind)),
+                ("message", self.message.encode(env)),
+                ("details", terms::map(env, details)),
+            ],
+        )
+    }
+}
+
+/// Run the body of a NIF, turning a panic into an `:internal_error` error
+/// instead of letting it reach the VM as an opaque NIF crash.
+pub fn guard<T>(f: impl FnOnce() -> NifResult<T>) -> NifResult<T> {
+    panic::catch_unwind(AssertUnwindSafe(f))
+        .unwrap_or_else(|payload| Err(panic_error(payload).into()))
+}
+
+/// The error reported for a caught panic, with the panic message if it has one.
+pub fn panic_error(payload: Box<dyn std::any::Any + Send>) -> Error {
+    let message = match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => match payload.downcast::<&str>() {
+            Ok(message) => (*message).to_owned(),
+            Err(_) => "unknown panic".to_owned(),
+        },
+    };
+    Error::new("internal_error", format!("panic: {message}"))
+}
 
 /// Convert a MontyException to a Rustler error with a descriptive term.
 pub fn monty_exception_to_rustler_error(exc: MontyException) -> rustler::Error {
-    rustler::Error::Term(Box::new(ExceptionWrapper(exc)))
+    rustler::Error::Term(Box::new(ExceptionWrapper(exc, None)))
 }
 
-/// Convert a ResourceError to a Rustler error with a descriptive term.
-#[allow(dead_code)]
-pub fn resource_error_to_rustler_error(err: ResourceError) -> rustler::Error {
-    rustler::Error::Term(Box::new(ResourceErrorWrapper(err)))
+/// Convert a failure to compile a script into a Rustler error: an exception
+/// of kind `:compile_error` that lists the problem in its `diagnostics` field.
+pub fn compile_error(exc: MontyException) -> rustler::Error {
+    rustler::Error::Term(Box::new(CompileErrorWrapper(exc)))
 }
 
-struct ExceptionWrapper(MontyException);
+/// The limit that stopped an execution, reported in the `limit` field of
+/// `%ExMonty.Exception{}`.
+pub enum LimitExceeded {
+    Resource(ResourceError),
+    Output { limit: usize, written: usize },
+}
+
+/// Convert the outcome of an interpreter step into a NIF result.
+///
+/// A cancelled step fails with an error of kind `:cancelled`. Exceptions caused by an exceeded
+/// limit carry the structured limit, so callers can tell a quota hit from a
+/// bug in the script. An exceeded output limit fails the step even if the
+/// script caught the exception raised by `print()`.
+pub fn check_step<T>(
+    result: Result<T, MontyException>,
+    report: StepReport,
+    output: &OutputBudget,
+) -> NifResult<T> {
+    if report.cancelled {
+        return Err(Error::new("cancelled", "execution cancelled").into());
+    }
+
+    if let Some((limit, written)) = output.exceeded() {
+        let exc = match result {
+            Err(exc) => exc,
+            Ok(_) => MontyException::new(
+                ExcType::RuntimeError,
+                Some("output limit exceeded".to_owned()),
+            ),
+        };
+        let limit = LimitExceeded::Output { limit, written };
+        return Err(rustler::Error::Term(Box::new(ExceptionWrapper(
+            exc,
+            Some(limit),
+        ))));
+    }
+
+    result.map_err(|exc| {
+        // A script may catch a limit's exception and go on to fail with
+        // another one, so the limit is only reported with its own exception.
+        let limit = report
+            .tripped
+            .filter(|err| limit_exc_type(err) == Some(exc.exc_type()))
+            .map(LimitExceeded::Resource);
+        rustler::Error::Term(Box::new(ExceptionWrapper(exc, limit)))
+    })
+}
+
+/// The exception the interpreter raises when `err`'s limit is exceeded.
+fn limit_exc_type(err: &ResourceError) -> Option<ExcType> {
+    match err {
+        ResourceError::Allocation { .. } | ResourceError::Memory { .. } => {
+            Some(ExcType::MemoryError)
+        }
+        ResourceError::Time { .. } => Some(ExcType::TimeoutError),
+        ResourceError::Recursion { .. } => Some(ExcType::RecursionError),
+        ResourceError::Exception(_) => None,
+    }
+}
+
+/// Encode a NIF error the way the Elixir wrappers report it: `{:error, reason}`.
+///
+/// Used where no NIF return value carries the error back, such as results
+/// sent as messages by the async NIFs. Raised errors are reported the same
+/// way the wrappers rescue them.
+pub fn encode_error_tuple(env: Env<'_>, err: rustler::Error) -> Term<'_> {
+    let reason = match err {
+        rustler::Error::BadArg => rustler::types::atom::badarg().encode(env),
+        rustler::Error::Atom(name) | rustler::Error::RaiseAtom(name) => terms::atom(env, name),
+        rustler::Error::Term(term) | rustler::Error::RaiseTerm(term) => term.encode(env),
+    };
+    rustler::types::tuple::make_tuple(env, &[rustler::types::atom::error().encode(env), reason])
+}
+
+struct ExceptionWrapper(MontyException, Option<LimitExceeded>);
 
 impl Encoder for ExceptionWrapper {
     fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
-        encode_monty_exception(env, &self.0)
+        let kind = match self.1 {
+            Some(_) => "limit_exceeded",
+            None => "python_exception",
+        };
+        encode_monty_exception(env, kind, &self.0, self.1.as_ref(), &[])
     }
 }
 
-#[allow(dead_code)]
-struct ResourceErrorWrapper(ResourceError);
+struct CompileErrorWrapper(MontyException);
 
-impl Encoder for ResourceErrorWrapper {
+impl Encoder for CompileErrorWrapper {
     fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
-        encode_resource_error(env, &self.0)
+        let diagnostics = [encode_diagnostic(env, &self.0)];
+        encode_monty_exception(env, "compile_error", &self.0, None, &diagnostics)
     }
 }
 
+/// Encode where and why compiling failed as `%ExMonty.Diagnostic{}`. The
+/// location is the innermost traceback frame's span, or `nil` if there is
+/// no frame.
+///
+/// The parser stops at the first error, so a compile error has exactly one
+/// diagnostic.
+fn encode_diagnostic<'a>(env: Env<'a>, exc: &MontyException) -> Term<'a> {
+    let nil = terms::nil(env);
+    let frame = exc.traceback().last();
+    let message = exc.message().unwrap_or_default();
+
+    terms::make_struct(
+        env,
+        "ExMonty.Diagnostic",
+        &[
+            ("line", frame.map_or(nil, |f| f.start.line.encode(env))),
+            ("column", frame.map_or(nil, |f| f.start.column.encode(env))),
+            ("end_line", frame.map_or(nil, |f| f.end.line.encode(env))),
+            (
+                "end_column",
+                frame.map_or(nil, |f| f.end.column.encode(env)),
+            ),
+            ("message", message.encode(env)),
+        ],
+    )
+}
+
 /// Encode a MontyException as an Elixir-friendly term:
-/// %ExMonty.Exception{type: atom, message: string | nil, traceback: [frame]}
-pub fn encode_monty_exception<'a>(env: Env<'a>, exc: &MontyException) -> Term<'a> {
-    let exc_type_str = exc.exc_type().to_string();
-    let exc_type_atom = rustler::types::atom::Atom::from_str(env, &snake_case(&exc_type_str))
-        .unwrap()
-        .encode(env);
+/// %ExMonty.Exception{kind: atom, type: atom, message: string | nil, args: [string],
+/// traceback: [frame], limit: tuple | nil, diagnostics: [diagnostic]}
+pub fn encode_monty_exception<'a>(
+    env: Env<'a>,
+    kind: &str,
+    exc: &MontyException,
+    limit: Option<&LimitExceeded>,
+    diagnostics: &[Term<'a>],
+) -> Term<'a> {
+    let exc_type = terms::atom(env, &snake_case(&exc.exc_type().to_string()));
 
     let message = match exc.message() {
         Some(msg) => msg.encode(env),
-        None => rustler::types::atom::nil().encode(env),
+        None => terms::nil(env),
     };
 
     let traceback: Vec<Term> = exc
@@ -48,144 +276,109 @@ pub fn encode_monty_exception<'a>(env: Env<'a>, exc: &MontyException) -> Term<'a
         .map(|frame| encode_stack_frame(env, frame))
         .collect();
 
-    let struct_atom =
-        rustler::types::atom::Atom::from_str(env, "Elixir.ExMonty.Exception").unwrap();
+    let limit = match limit {
+        Some(LimitExceeded::Resource(err)) => encode_resource_error(env, err),
+        Some(LimitExceeded::Output { limit, written }) => terms::tagged(
+            env,
+            "output_limit",
+            &[limit.encode(env), written.encode(env)],
+        ),
+        None => terms::nil(env),
+    };
 
-    rustler::types::map::map_new(env)
-        .map_put(
-            rustler::types::atom::Atom::from_str(env, "__struct__")
-                .unwrap()
-                .encode(env),
-            struct_atom.encode(env),
-        )
-        .unwrap()
-        .map_put(
-            rustler::types::atom::Atom::from_str(env, "type")
-                .unwrap()
-                .encode(env),
-            exc_type_atom,
-        )
-        .unwrap()
-        .map_put(
-            rustler::types::atom::Atom::from_str(env, "message")
-                .unwrap()
-                .encode(env),
-            message,
-        )
-        .unwrap()
-        .map_put(
-            rustler::types::atom::Atom::from_str(env, "traceback")
-                .unwrap()
-                .encode(env),
-            traceback.encode(env),
-        )
-        .unwrap()
+    terms::make_struct(
+        env,
+        "ExMonty.Exception",
+        &[
+            ("kind", terms::atom(env, kind)),
+            ("type", exc_type),
+            ("message", message),
+            (
+                "args",
+                exc.message().into_iter().collect::<Vec<_>>().encode(env),
+            ),
+            ("traceback", traceback.encode(env)),
+            ("limit", limit),
+            ("diagnostics", diagnostics.encode(env)),
+        ],
+    )
 }
 
 fn encode_stack_frame<'a>(env: Env<'a>, frame: &monty::StackFrame) -> Term<'a> {
-    let struct_atom =
-        rustler::types::atom::Atom::from_str(env, "Elixir.ExMonty.StackFrame").unwrap();
-
-    let filename = frame.filename.encode(env);
-    let line = frame.start.line.encode(env);
-    let column = frame.start.column.encode(env);
-    let end_line = frame.end.line.encode(env);
-    let end_column = frame.end.column.encode(env);
     let frame_name = match &frame.frame_name {
         Some(name) => name.encode(env),
-        None => rustler::types::atom::nil().encode(env),
+        None => terms::nil(env),
+    };
+    let source = match &frame.preview_line {
+        Some(line) => line.encode(env),
+        None => terms::nil(env),
+    };
+    let caret = match frame
+        .preview_line
+        .as_deref()
+        .and_then(|l| caret_range(frame, l))
+    {
+        Some((first, last)) => {
+            rustler::types::tuple::make_tuple(env, &[first.encode(env), last.encode(env)])
+        }
+        None => terms::nil(env),
     };
 
-    rustler::types::map::map_new(env)
-        .map_put(
-            rustler::types::atom::Atom::from_str(env, "__struct__")
-                .unwrap()
-                .encode(env),
-            struct_atom.encode(env),
-        )
-        .unwrap()
-        .map_put(
-            rustler::types::atom::Atom::from_str(env, "filename")
-                .unwrap()
-                .encode(env),
-            filename,
-        )
-        .unwrap()
-        .map_put(
-            rustler::types::atom::Atom::from_str(env, "line")
-                .unwrap()
-                .encode(env),
-            line,
-        )
-        .unwrap()
-        .map_put(
-            rustler::types::atom::Atom::from_str(env, "column")
-                .unwrap()
-                .encode(env),
-            column,
-        )
-        .unwrap()
-        .map_put(
-            rustler::types::atom::Atom::from_str(env, "end_line")
-                .unwrap()
-                .encode(env),
-            end_line,
-        )
-        .unwrap()
-        .map_put(
-            rustler::types::atom::Atom::from_str(env, "end_column")
-                .unwrap()
-                .encode(env),
-            end_column,
-        )
-        .unwrap()
-        .map_put(
-            rustler::types::atom::Atom::from_str(env, "name")
-                .unwrap()
-                .encode(env),
-            frame_name,
-        )
-        .unwrap()
+    terms::make_struct(
+        env,
+        "ExMonty.StackFrame",
+        &[
+            ("filename", frame.filename.encode(env)),
+            ("line", frame.start.line.encode(env)),
+            ("column", frame.start.column.encode(env)),
+            ("end_line", frame.end.line.encode(env)),
+            ("end_column", frame.end.column.encode(env)),
+            ("name", frame_name),
+            ("source", source),
+            ("caret", caret),
+        ],
+    )
+}
+
+/// Columns (1-based, inclusive) of the first and last character of `line`
+/// covered by the frame's span. A span running past the end of its first
+/// line is cut off there, as in CPython tracebacks.
+fn caret_range(frame: &monty::StackFrame, line: &str) -> Option<(usize, usize)> {
+    let first = usize::try_from(frame.start.column).ok()?;
+    let last = if frame.end.line == frame.start.line {
+        usize::try_from(frame.end.column).ok()?.checked_sub(1)?
+    } else {
+        line.chars().count()
+    };
+    (first >= 1 && last >= first).then_some((first, last))
 }
 
-#[allow(dead_code)]
 fn encode_resource_error<'a>(env: Env<'a>, err: &ResourceError) -> Term<'a> {
     match err {
-        ResourceError::Allocation { limit, count } => {
-            let tag = rustler::types::atom::Atom::from_str(env, "allocation_limit").unwrap();
-            rustler::types::tuple::make_tuple(
-                env,
-                &[tag.encode(env), limit.encode(env), count.encode(env)],
-            )
-        }
-        ResourceError::Time { limit, elapsed } => {
-            let tag = rustler::types::atom::Atom::from_str(env, "time_limit").unwrap();
-            let limit_secs = limit.as_secs_f64();
-            let elapsed_secs = elapsed.as_secs_f64();
-            rustler::types::tuple::make_tuple(
-                env,
-                &[
-                    tag.encode(env),
-                    limit_secs.encode(env),
-                    elapsed_secs.encode(env),
-                ],
-            )
-        }
+        ResourceError::Allocation { limit, count } => terms::tagged(
+            env,
+            "allocation_limit",
+            &[limit.encode(env), count.encode(env)],
+        ),
+        ResourceError::Time { limit, elapsed } => terms::tagged(
+            env,
+            "time_limit",
+            &[
+                limit.as_secs_f64().encode(env),
+                elapsed.as_secs_f64().encode(env),
+            ],
+        ),
         ResourceError::Memory { limit, used } => {
-            let tag = rustler::types::atom::Atom::from_str(env, "memory_limit").unwrap();
-            rustler::types::tuple::make_tuple(
-                env,
-                &[tag.encode(env), limit.encode(env), used.encode(env)],
-            )
+            terms::tagged(env, "memory_limit", &[limit.encode(env), used.encode(env)])
         }
-        ResourceError::Recursion { limit, depth } => {
-            let tag = rustler::types::atom::Atom::from_str(env, "recursion_limit").unwrap();
-            rustler::types::tuple::make_tuple(
-                env,
-                &[tag.encode(env), limit.encode(env), depth.encode(env)],
-            )
+        ResourceError::Recursion { limit, depth } => terms::tagged(
+            env,
+            "recursion_limit",
+            &[limit.encode(env), depth.encode(env)],
+        ),
+        ResourceError::Exception(exc) => {
+            encode_monty_exception(env, "python_exception", exc, None, &[])
         }
-        ResourceError::Exception(exc) => encode_monty_exception(env, exc),
     }
 }
 
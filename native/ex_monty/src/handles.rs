@@ -0,0 +1,90 @@
+use monty::MontyObject;
+use rustler::{sys, Env, Term, TermType};
+use std::collections::HashMap;
+use std::mem::MaybeUninit;
+
+/// Erlang terms that have no Python equivalent (pids, references, funs and
+/// ports), passed into Python as opaque handles.
+///
+/// Python sees an opaque object whose repr is `<Handle #n>` and which has no
+/// attributes. When a handle comes back in a call argument or a result it is
+/// encoded as the original term again.
+/// Terms are kept in external term format, so the table can travel with a
+/// snapshot through dump/load. Ids start at 1 and are never reused within an
+/// execution; the same term always gets the same id.
+///
+/// `ids` maps each term back to its id. It is not serialized and is rebuilt
+/// on the first insert after a load.
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct HandleTable {
+    terms: Vec<Vec<u8>>,
+    #[serde(skip)]
+    ids: HashMap<Vec<u8>, usize>,
+}
+
+impl HandleTable {
+    /// Whether `term` can only be passed to Python as a handle.
+    pub fn is_opaque(term: Term) -> bool {
+        matches!(
+            term.get_type(),
+            TermType::Pid | TermType::Ref | TermType::Fun | TermType::Port
+        )
+    }
+
+    /// Add `term` to the table and return its handle object.
+    pub fn insert(&mut self, term: Term) -> MontyObject {
+        if self.ids.len() != self.terms.len() {
+            self.ids = self.terms.iter().cloned().zip(1..).collect();
+        }
+        let bytes = term.to_binary().as_slice().to_vec();
+        let id = match self.ids.get(&bytes) {
+            Some(&id) => id,
+            None => {
+                self.terms.push(bytes.clone());
+                self.ids.insert(bytes, self.terms.len());
+                self.terms.len()
+            }
+        };
+
+        MontyObject::Repr(format!("<Handle #{id}>"))
+    }
+
+    /// The id in a handle's repr, or `None` if `repr` is not one. Inputs
+    /// with such a repr are refused, so only `insert` makes handles.
+    pub fn parse_repr(repr: &str) -> Option<usize> {
+        let id = repr.strip_prefix("<Handle #")?.strip_suffix('>')?;
+        id.parse().ok()
+    }
+
+    /// The term behind a handle object, or `None` if `obj` is not a handle
+    /// from this table. A table loaded from a dump is not trusted: its terms
+    /// are decoded without creating atoms, and anything but a pid, reference,
+    /// fun or port is rejected.
+    pub fn lookup<'a>(&self, env: Env<'a>, obj: &MontyObject) -> Option<Term<'a>> {
+        let MontyObject::Repr(repr) = obj else {
+            return None;
+        };
+        let index = Self::parse_repr(repr)?.checked_sub(1)?;
+        let term = binary_to_term_safe(env, self.terms.get(index)?)?;
+        Self::is_opaque(term).then_some(term)
+    }
+}
+
+/// Decode external term format with `ERL_NIF_BIN2TERM_SAFE`, which refuses
+/// to create atoms or external funs, so a crafted dump cannot exhaust the
+/// atom table.
+fn binary_to_term_safe<'a>(env: Env<'a>, bytes: &[u8]) -> Option<Term<'a>> {
+    let mut term = MaybeUninit::uninit();
+    // SAFETY: `bytes` outlives the call, `term` is only read when the call
+    // reports that it decoded something, and the decoded term belongs to `env`.
+    unsafe {
+        let read = sys::enif_binary_to_term(
+            env.as_c_arg(),
+            bytes.as_ptr(),
+            bytes.len(),
+            term.as_mut_ptr(),
+            sys::ErlNifBinaryToTerm::ERL_NIF_BIN2TERM_SAFE,
+        );
+        (read == bytes.len()).then(|| Term::new(env, term.assume_init()))
+    }
+}
@@ -1,12 +1,22 @@
 use monty::{
-    CollectStringPrint, ExternalResult, LimitedTracker, MontyException, MontyObject, RunProgress,
+    ExternalResult, FutureSnapshot, MontyException, MontyObject, ReplFutureSnapshot, ReplSnapshot,
+    RunProgress, Snapshot,
 };
 use rustler::types::atom::Atom;
 use rustler::{Encoder, Env, NifResult, ResourceArc, Term};
 
-use crate::error;
-use crate::resources::{FutureSnapshotResource, RunnerResource, SnapshotResource};
-use crate::types;
+use crate::background;
+use crate::error::{self, Error};
+use crate::handles::HandleTable;
+use crate::resources::{
+    ExecState, FutureSnapshotResource, RunnerResource, SessionFutureSnapshotResource,
+    SessionResource, SessionSnapshotResource, SnapshotResource,
+};
+use crate::session;
+use crate::step::StepOpts;
+use crate::terms;
+use crate::tracker::{self, ExTracker};
+use crate::types::{self, DecodeOpts, Decoder, EncodeOpts, Limits};
 
 #[rustler::nif(schedule = "DirtyCpu")]
 fn start<'a>(
@@ -14,87 +24,380 @@ fn start<'a>(
     runner: ResourceArc<RunnerResource>,
     inputs: Vec<(String, Term<'a>)>,
     limits: Term<'a>,
+    opts: Term<'a>,
 ) -> NifResult<Term<'a>> {
-    let monty_run = runner.clone_runner();
-    let monty_inputs = types::decode_inputs(env, inputs, runner.input_names())?;
-    let resource_limits = types::decode_resource_limits(limits)?;
-    let tracker = LimitedTracker::new(resource_limits);
-    let mut print = CollectStringPrint::new();
-
-    let progress = monty_run
-        .start(monty_inputs, tracker, &mut print)
-        .map_err(|e| error::monty_exception_to_rustler_error(e))?;
-
-    let output = print.into_output();
-    encode_run_progress(env, progress, &output)
+    error::guard(|| {
+        let decode = DecodeOpts::decode(opts)?;
+        let opts = StepOpts::decode(env, opts)?;
+        Step::start(env, runner, inputs, limits, decode)?.execute(env, opts)
+    })
 }
 
 #[rustler::nif(schedule = "DirtyCpu")]
 fn resume<'a>(
     env: Env<'a>,
-    snapshot: ResourceArc<SnapshotResource>,
+    snapshot: Term<'a>,
     result: Term<'a>,
+    opts: Term<'a>,
 ) -> NifResult<Term<'a>> {
-    let snap = snapshot
-        .take()
-        .ok_or_else(|| rustler::Error::RaiseTerm(Box::new("snapshot already consumed")))?;
-
-    let external_result = decode_external_result(env, result)?;
-    let mut print = CollectStringPrint::new();
-
-    let progress = snap
-        .run(external_result, &mut print)
-        .map_err(|e| error::monty_exception_to_rustler_error(e))?;
-
-    let output = print.into_output();
-    encode_run_progress(env, progress, &output)
+    error::guard(|| {
+        let decode = DecodeOpts::decode(opts)?;
+        let opts = StepOpts::decode(env, opts)?;
+        Step::resume(env, snapshot, result, decode)?.execute(env, opts)
+    })
 }
 
 #[rustler::nif(schedule = "DirtyCpu")]
 fn resume_futures<'a>(
     env: Env<'a>,
-    futures: ResourceArc<FutureSnapshotResource>,
+    futures: Term<'a>,
     results: Vec<(u32, Term<'a>)>,
+    opts: Term<'a>,
 ) -> NifResult<Term<'a>> {
-    let future_snap = futures
-        .take()
-        .ok_or_else(|| rustler::Error::RaiseTerm(Box::new("future snapshot already consumed")))?;
-
-    let external_results: Vec<(u32, ExternalResult)> = results
-        .into_iter()
-        .map(|(id, term)| {
-            let result = decode_external_result(env, term)?;
-            Ok((id, result))
-        })
-        .collect::<NifResult<Vec<_>>>()?;
+    error::guard(|| {
+        let decode = DecodeOpts::decode(opts)?;
+        let opts = StepOpts::decode(env, opts)?;
+        Step::resume_futures(env, futures, results, decode)?.execute(env, opts)
+    })
+}
 
-    let mut print = CollectStringPrint::new();
+#[rustler::nif]
+fn start_async<'a>(
+    env: Env<'a>,
+    runner: ResourceArc<RunnerResource>,
+    inputs: Term<'a>,
+    limits: Term<'a>,
+    opts: Term<'a>,
+    reference: Term<'a>,
+) -> NifResult<Atom> {
+    error::guard(|| {
+        let reply = background::Reply::new(env, opts, reference)?;
+        let decode = DecodeOpts::decode(opts)?;
+        types::decode_resource_limits(limits)?;
+        let (inputs, limits) = (reply.save(inputs), reply.save(limits));
+        Ok(reply.spawn(move |env, opts| {
+            let inputs = inputs.load(env).decode()?;
+            let step = Step::start(env, runner, inputs, limits.load(env), decode)?;
+            Ok(background::ok(env, step.execute(env, opts)?))
+        }))
+    })
+}
 
-    let progress = future_snap
-        .resume(external_results, &mut print)
-        .map_err(|e| error::monty_exception_to_rustler_error(e))?;
+#[rustler::nif]
+fn resume_async<'a>(
+    env: Env<'a>,
+    snapshot: Term<'a>,
+    result: Term<'a>,
+    opts: Term<'a>,
+    reference: Term<'a>,
+) -> NifResult<Atom> {
+    error::guard(|| {
+        let reply = background::Reply::new(env, opts, reference)?;
+        let decode = DecodeOpts::decode(opts)?;
+        check_unconsumed(snapshot, "snapshot")?;
+        let (snapshot, result) = (reply.save(snapshot), reply.save(result));
+        Ok(reply.spawn(move |env, opts| {
+            let step = Step::resume(env, snapshot.load(env), result.load(env), decode)?;
+            Ok(background::ok(env, step.execute(env, opts)?))
+        }))
+    })
+}
 
-    let output = print.into_output();
-    encode_run_progress(env, progress, &output)
+#[rustler::nif]
+fn resume_futures_async<'a>(
+    env: Env<'a>,
+    futures: Term<'a>,
+    results: Term<'a>,
+    opts: Term<'a>,
+    reference: Term<'a>,
+) -> NifResult<Atom> {
+    error::guard(|| {
+        let reply = background::Reply::new(env, opts, reference)?;
+        let decode = DecodeOpts::decode(opts)?;
+        check_unconsumed(futures, "future snapshot")?;
+        let (futures, results) = (reply.save(futures), reply.save(results));
+        Ok(reply.spawn(move |env, opts| {
+            let results = results.load(env).decode()?;
+            let step = Step::resume_futures(env, futures.load(env), results, decode)?;
+            Ok(background::ok(env, step.execute(env, opts)?))
+        }))
+    })
 }
 
 #[rustler::nif]
-fn pending_call_ids(futures: ResourceArc<FutureSnapshotResource>) -> NifResult<Vec<u32>> {
-    futures
-        .with(|snap| snap.pending_call_ids().to_vec())
-        .ok_or_else(|| rustler::Error::RaiseTerm(Box::new("future snapshot already consumed")))
+fn pending_call_ids(futures: Term) -> NifResult<Vec<u32>> {
+    error::guard(|| {
+        let ids = if let Ok(futures) = futures.decode::<ResourceArc<FutureSnapshotResource>>() {
+            futures.with(|snap| snap.pending_call_ids().to_vec())
+        } else {
+            let futures: ResourceArc<SessionFutureSnapshotResource> = futures.decode()?;
+            futures.with(|snap| snap.pending_call_ids().to_vec())
+        };
+        ids.ok_or_else(|| Error::consumed_snapshot("future snapshot").raise())
+    })
+}
+
+#[rustler::nif]
+fn remaining_budget<'a>(env: Env<'a>, snapshot: Term<'a>) -> NifResult<Term<'a>> {
+    error::guard(|| {
+        let encode = |state: &ExecState| {
+            tracker::encode_remaining(env, &state.budget, &state.usage, state.output.remaining())
+        };
+
+        let remaining = if let Ok(snap) = snapshot.decode::<ResourceArc<SnapshotResource>>() {
+            snap.with_state(encode)
+        } else if let Ok(futures) = snapshot.decode::<ResourceArc<FutureSnapshotResource>>() {
+            futures.with_state(encode)
+        } else if let Ok(snap) = snapshot.decode::<ResourceArc<SessionSnapshotResource>>() {
+            snap.with_state(encode)
+        } else {
+            let futures: ResourceArc<SessionFutureSnapshotResource> = snapshot.decode()?;
+            futures.with_state(encode)
+        };
+
+        remaining.ok_or_else(|| Error::consumed_snapshot("snapshot").raise())
+    })
 }
 
 // ── Helpers ──────────────────────────────────────────────────────────────────
 
+/// Fail right away if `snapshot`, of any kind, was already consumed, so an
+/// async resume of it is refused before anything is queued. `what` names it
+/// in the error.
+fn check_unconsumed(snapshot: Term, what: &str) -> NifResult<()> {
+    let live = if let Ok(snap) = snapshot.decode::<ResourceArc<SnapshotResource>>() {
+        snap.with_state(|_| ())
+    } else if let Ok(futures) = snapshot.decode::<ResourceArc<FutureSnapshotResource>>() {
+        futures.with_state(|_| ())
+    } else if let Ok(snap) = snapshot.decode::<ResourceArc<SessionSnapshotResource>>() {
+        snap.with_state(|_| ())
+    } else {
+        let futures: ResourceArc<SessionFutureSnapshotResource> = snapshot.decode()?;
+        futures.with_state(|_| ())
+    };
+    live.ok_or_else(|| Error::consumed_snapshot(what).into())
+}
+
+/// A decoded interactive step, ready to execute on this thread or the pool.
+enum Step {
+    Start {
+        runner: ResourceArc<RunnerResource>,
+        inputs: Vec<MontyObject>,
+        limits: Limits,
+        handles: HandleTable,
+    },
+    Resume {
+        snapshot: Snapshot<ExTracker>,
+        state: ExecState,
+        result: ExternalResult,
+    },
+    ResumeFutures {
+        snapshot: FutureSnapshot<ExTracker>,
+        state: ExecState,
+        results: Vec<(u32, ExternalResult)>,
+    },
+    ResumeCell {
+        snapshot: ReplSnapshot<ExTracker>,
+        state: ExecState,
+        result: ExternalResult,
+        session: ResourceArc<SessionResource>,
+    },
+    ResumeCellFutures {
+        snapshot: ReplFutureSnapshot<ExTracker>,
+        state: ExecState,
+        results: Vec<(u32, ExternalResult)>,
+        session: ResourceArc<SessionResource>,
+    },
+}
+
+impl Step {
+    fn start<'a>(
+        env: Env<'a>,
+        runner: ResourceArc<RunnerResource>,
+        inputs: Vec<(String, Term<'a>)>,
+        limits: Term<'a>,
+        decode: DecodeOpts,
+    ) -> NifResult<Self> {
+        let mut handles = HandleTable::default();
+        let mut decoder = Decoder::new(decode, &mut handles);
+        let inputs = types::decode_inputs(env, inputs, runner.input_names(), &mut decoder)?;
+        let limits = types::decode_resource_limits(limits)?;
+        Ok(Step::Start {
+            runner,
+            inputs,
+            limits,
+            handles,
+        })
+    }
+
+    /// Decode a resume of either a plain snapshot or a session snapshot.
+    ///
+    /// The result is decoded against a copy of the execution's handle table
+    /// before the snapshot is taken, so a result that fails to decode leaves
+    /// the snapshot usable.
+    fn resume<'a>(
+        env: Env<'a>,
+        snapshot: Term<'a>,
+        result: Term<'a>,
+        decode: DecodeOpts,
+    ) -> NifResult<Self> {
+        let consumed = || Error::consumed_snapshot("snapshot");
+        let handles_of = |state: &ExecState| state.handles.clone();
+
+        if let Ok(snapshot) = snapshot.decode::<ResourceArc<SnapshotResource>>() {
+            let mut handles = snapshot.with_state(handles_of).ok_or_else(consumed)?;
+            let mut decoder = Decoder::new(decode, &mut handles);
+            let result = decode_external_result(env, "result", result, &mut decoder)?;
+            let (snapshot, mut state) = snapshot.take().ok_or_else(consumed)?;
+            state.handles = handles;
+            return Ok(Step::Resume {
+                snapshot,
+                state,
+                result,
+            });
+        }
+
+        let cell: ResourceArc<SessionSnapshotResource> = snapshot.decode()?;
+        let mut handles = cell.with_state(handles_of).ok_or_else(consumed)?;
+        let mut decoder = Decoder::new(decode, &mut handles);
+        let result = decode_external_result(env, "result", result, &mut decoder)?;
+        let (snapshot, mut state) = cell.take().ok_or_else(consumed)?;
+        state.handles = handles;
+        Ok(Step::ResumeCell {
+            snapshot,
+            state,
+            result,
+            session: cell.session(),
+        })
+    }
+
+    fn resume_futures<'a>(
+        env: Env<'a>,
+        futures: Term<'a>,
+        results: Vec<(u32, Term<'a>)>,
+        decode: DecodeOpts,
+    ) -> NifResult<Self> {
+        let consumed = || Error::consumed_snapshot("future snapshot");
+        let handles_of = |state: &ExecState| state.handles.clone();
+        let decode_results = |handles: &mut HandleTable| {
+            let mut decoder = Decoder::new(decode, handles);
+            results
+                .into_iter()
+                .map(|(id, term)| {
+                    let name = format!("results[{id}]");
+                    let result = decode_external_result(env, &name, term, &mut decoder)?;
+                    Ok((id, result))
+                })
+                .collect::<NifResult<Vec<_>>>()
+        };
+
+        if let Ok(futures) = futures.decode::<ResourceArc<FutureSnapshotResource>>() {
+            let mut handles = futures.with_state(handles_of).ok_or_else(consumed)?;
+            let results = decode_results(&mut handles)?;
+            let (snapshot, mut state) = futures.take().ok_or_else(consumed)?;
+            state.handles = handles;
+            return Ok(Step::ResumeFutures {
+                snapshot,
+                state,
+                results,
+            });
+        }
+
+        let cell: ResourceArc<SessionFutureSnapshotResource> = futures.decode()?;
+        let mut handles = cell.with_state(handles_of).ok_or_else(consumed)?;
+        let results = decode_results(&mut handles)?;
+        let (snapshot, mut state) = cell.take().ok_or_else(consumed)?;
+        state.handles = handles;
+        Ok(Step::ResumeCellFutures {
+            snapshot,
+            state,
+            results,
+            session: cell.session(),
+        })
+    }
+
+    fn execute<'a>(self, env: Env<'a>, opts: StepOpts<'a>) -> NifResult<Term<'a>> {
+        let stats = opts.stats;
+        let encode = opts.encode;
+        let (progress, output, state) = match self {
+            Step::Start {
+                runner,
+                inputs,
+                limits,
+                handles,
+            } => {
+                let monty_run = runner.clone_runner();
+                let tracker = ExTracker::new(limits.resource);
+                let state = ExecState::new(limits.output, limits.budget, handles);
+                opts.run(state, |print| monty_run.start(inputs, tracker, print))
+            }
+            Step::Resume {
+                snapshot,
+                state,
+                result,
+            } => opts.run(state, |print| snapshot.run(result, print)),
+            Step::ResumeFutures {
+                snapshot,
+                state,
+                results,
+            } => opts.run(state, |print| snapshot.resume(results, print)),
+            Step::ResumeCell {
+                snapshot,
+                state,
+                result,
+                session: cell_session,
+            } => {
+                let _reset = session::ResetOnPanic(&cell_session);
+                let (progress, output, state) = opts.run(state, |print| {
+                    session::settle(&cell_session, snapshot.run(result, print))
+                });
+                return session::encode_cell_progress(
+                    env,
+                    progress,
+                    &cell_session,
+                    state,
+                    &output,
+                    stats,
+                    &encode,
+                );
+            }
+            Step::ResumeCellFutures {
+                snapshot,
+                state,
+                results,
+                session: cell_session,
+            } => {
+                let _reset = session::ResetOnPanic(&cell_session);
+                let (progress, output, state) = opts.run(state, |print| {
+                    session::settle(&cell_session, snapshot.resume(results, print))
+                });
+                return session::encode_cell_progress(
+                    env,
+                    progress,
+                    &cell_session,
+                    state,
+                    &output,
+                    stats,
+                    &encode,
+                );
+            }
+        };
+        encode_run_progress(env, progress?, state, &output, stats, &encode)
+    }
+}
+
 fn encode_run_progress<'a>(
     env: Env<'a>,
-    progress: RunProgress<LimitedTracker>,
+    progress: RunProgress<ExTracker>,
+    exec_state: ExecState,
     output: &str,
+    stats: bool,
+    encode: &EncodeOpts,
 ) -> NifResult<Term<'a>> {
     let output_term = output.encode(env);
+    let stats_term = stats.then(|| tracker::encode_usage(env, &exec_state.usage));
 
-    match progress {
+    let mut elements = match progress {
         RunProgress::FunctionCall {
             function_name,
             args,
@@ -102,13 +405,18 @@ fn encode_run_progress<'a>(
             call_id,
             state,
         } => {
-            let tag = Atom::from_str(env, "function_call").unwrap();
-            let call = encode_function_call(env, &function_name, &args, &kwargs, call_id);
-            let snapshot_ref = ResourceArc::new(SnapshotResource::new(state));
-            Ok(rustler::types::tuple::make_tuple(
+            let tag = terms::atom(env, "function_call");
+            let call = encode_function_call(
                 env,
-                &[tag.encode(env), call, snapshot_ref.encode(env), output_term],
-            ))
+                &function_name,
+                &args,
+                &kwargs,
+                call_id,
+                encode,
+                &exec_state.handles,
+            );
+            let snapshot_ref = ResourceArc::new(SnapshotResource::new(state, exec_state));
+            vec![tag, call, snapshot_ref.encode(env), output_term]
         }
         RunProgress::OsCall {
             function,
@@ -117,132 +425,117 @@ fn encode_run_progress<'a>(
             call_id,
             state,
         } => {
-            let tag = Atom::from_str(env, "os_call").unwrap();
-            let call = encode_os_call(env, &function, &args, &kwargs, call_id);
-            let snapshot_ref = ResourceArc::new(SnapshotResource::new(state));
-            Ok(rustler::types::tuple::make_tuple(
+            let tag = terms::atom(env, "os_call");
+            let call = encode_os_call(
                 env,
-                &[tag.encode(env), call, snapshot_ref.encode(env), output_term],
-            ))
+                &function,
+                &args,
+                &kwargs,
+                call_id,
+                encode,
+                &exec_state.handles,
+            );
+            let snapshot_ref = ResourceArc::new(SnapshotResource::new(state, exec_state));
+            vec![tag, call, snapshot_ref.encode(env), output_term]
         }
         RunProgress::ResolveFutures(future_snapshot) => {
-            let tag = Atom::from_str(env, "resolve_futures").unwrap();
-            let futures_ref = ResourceArc::new(FutureSnapshotResource::new(future_snapshot));
-            Ok(rustler::types::tuple::make_tuple(
-                env,
-                &[tag.encode(env), futures_ref.encode(env), output_term],
-            ))
+            let tag = terms::atom(env, "resolve_futures");
+            let futures_ref =
+                ResourceArc::new(FutureSnapshotResource::new(future_snapshot, exec_state));
+            vec![tag, futures_ref.encode(env), output_term]
         }
         RunProgress::Complete(value) => {
-            let tag = Atom::from_str(env, "complete").unwrap();
-            let value_term = types::encode_monty_object(env, &value);
-            Ok(rustler::types::tuple::make_tuple(
-                env,
-                &[tag.encode(env), value_term, output_term],
-            ))
+            let tag = terms::atom(env, "complete");
+            let value_term = types::encode_monty_object(env, &value, encode, &exec_state.handles);
+            vec![tag, value_term, output_term]
         }
-    }
+    };
+
+    // With `stats: true` every progress tuple gets the usage map as its last element.
+    elements.extend(stats_term);
+    Ok(rustler::types::tuple::make_tuple(env, &elements))
 }
 
-fn encode_function_call<'a>(
+pub fn encode_function_call<'a>(
     env: Env<'a>,
     name: &str,
     args: &[MontyObject],
     kwargs: &[(MontyObject, MontyObject)],
     call_id: u32,
+    encode: &EncodeOpts,
+    handles: &HandleTable,
 ) -> Term<'a> {
-    let struct_atom = Atom::from_str(env, "Elixir.ExMonty.FunctionCall").unwrap();
-
     let args_term: Vec<Term> = args
         .iter()
-        .map(|a| types::encode_monty_object(env, a))
+        .map(|a| types::encode_monty_object(env, a, encode, handles))
         .collect();
-    let kwargs_term = encode_kwargs(env, kwargs);
-
-    rustler::types::map::map_new(env)
-        .map_put(
-            Atom::from_str(env, "__struct__").unwrap().encode(env),
-            struct_atom.encode(env),
-        )
-        .unwrap()
-        .map_put(
-            Atom::from_str(env, "name").unwrap().encode(env),
-            name.encode(env),
-        )
-        .unwrap()
-        .map_put(
-            Atom::from_str(env, "args").unwrap().encode(env),
-            args_term.encode(env),
-        )
-        .unwrap()
-        .map_put(
-            Atom::from_str(env, "kwargs").unwrap().encode(env),
-            kwargs_term,
-        )
-        .unwrap()
-        .map_put(
-            Atom::from_str(env, "call_id").unwrap().encode(env),
-            call_id.encode(env),
-        )
-        .unwrap()
+    let kwargs_term = encode_kwargs(env, kwargs, encode, handles);
+
+    terms::make_struct(
+        env,
+        "ExMonty.FunctionCall",
+        &[
+            ("name", name.encode(env)),
+            ("args", args_term.encode(env)),
+            ("kwargs", kwargs_term),
+            ("call_id", call_id.encode(env)),
+        ],
+    )
 }
 
-fn encode_os_call<'a>(
+pub fn encode_os_call<'a>(
     env: Env<'a>,
     function: &monty::OsFunction,
     args: &[MontyObject],
     kwargs: &[(MontyObject, MontyObject)],
     call_id: u32,
+    encode: &EncodeOpts,
+    handles: &HandleTable,
 ) -> Term<'a> {
-    let struct_atom = Atom::from_str(env, "Elixir.ExMonty.OsCall").unwrap();
-
     let func_term = types::encode_os_function(env, function);
     let args_term: Vec<Term> = args
         .iter()
-        .map(|a| types::encode_monty_object(env, a))
+        .map(|a| types::encode_monty_object(env, a, encode, handles))
         .collect();
-    let kwargs_term = encode_kwargs(env, kwargs);
-
-    rustler::types::map::map_new(env)
-        .map_put(
-            Atom::from_str(env, "__struct__").unwrap().encode(env),
-            struct_atom.encode(env),
-        )
-        .unwrap()
-        .map_put(
-            Atom::from_str(env, "function").unwrap().encode(env),
-            func_term,
-        )
-        .unwrap()
-        .map_put(
-            Atom::from_str(env, "args").unwrap().encode(env),
-            args_term.encode(env),
-        )
-        .unwrap()
-        .map_put(
-            Atom::from_str(env, "kwargs").unwrap().encode(env),
-            kwargs_term,
-        )
-        .unwrap()
-        .map_put(
-            Atom::from_str(env, "call_id").unwrap().encode(env),
-            call_id.encode(env),
-        )
-        .unwrap()
+    let kwargs_term = encode_kwargs(env, kwargs, encode, handles);
+
+    terms::make_struct(
+        env,
+        "ExMonty.OsCall",
+        &[
+            ("function", func_term),
+            ("args", args_term.encode(env)),
+            ("kwargs", kwargs_term),
+            ("call_id", call_id.encode(env)),
+        ],
+    )
 }
 
-fn encode_kwargs<'a>(env: Env<'a>, kwargs: &[(MontyObject, MontyObject)]) -> Term<'a> {
-    let mut map = rustler::types::map::map_new(env);
-    for (k, v) in kwargs {
-        // kwargs keys are typically strings in Python
-        let key = types::encode_monty_object(env, k);
-        let val = types::encode_monty_object(env, v);
-        map = map.map_put(key, val).unwrap();
-    }
-    map
+fn encode_kwargs<'a>(
+    env: Env<'a>,
+    kwargs: &[(MontyObject, MontyObject)],
+    encode: &EncodeOpts,
+    handles: &HandleTable,
+) -> Term<'a> {
+    // kwargs keys are typically strings in Python
+    terms::map(
+        env,
+        kwargs.iter().map(|(k, v)| {
+            (
+                types::encode_monty_object(env, k, encode, handles),
+                types::encode_monty_object(env, v, encode, handles),
+            )
+        }),
+    )
 }
 
-fn decode_external_result<'a>(env: Env<'a>, term: Term<'a>) -> NifResult<ExternalResult> {
+/// Decode a handler result; `name` starts the path in decode errors.
+fn decode_external_result<'a>(
+    env: Env<'a>,
+    name: &str,
+    term: Term<'a>,
+    decoder: &mut Decoder,
+) -> NifResult<ExternalResult> {
     use rustler::types::tuple::get_tuple;
 
     if let Ok(elements) = get_tuple(term) {
@@ -250,29 +543,33 @@ fn decode_external_result<'a>(env: Env<'a>, term: Term<'a>) -> NifResult<Externa
             if let Ok(tag) = elements[0].atom_to_string() {
                 match tag.as_str() {
                     "ok" => {
-                        let obj = types::decode_monty_object(env, elements[1])?;
+                        let obj = decoder.decode_named(env, name, elements[1])?;
                         return Ok(ExternalResult::Return(obj));
                     }
                     "error" => {
-                        if elements.len() == 3 {
-                            let type_str: String = elements[1].decode().unwrap_or_else(|_| {
-                                elements[1]
-                                    .atom_to_string()
-                                    .unwrap_or_else(|_| "runtime_error".to_string())
-                            });
-                            let msg: String = elements[2]
-                                .decode()
-                                .unwrap_or_else(|_| "unknown error".to_string());
-                            let exc_type = parse_exc_type(&type_str);
-                            let exc = MontyException::new(exc_type, Some(msg));
-                            return Ok(ExternalResult::Error(exc));
-                        } else {
-                            let msg: String = elements[1]
-                                .decode()
-                                .unwrap_or_else(|_| "unknown error".to_string());
-                            let exc = MontyException::new(monty::ExcType::RuntimeError, Some(msg));
-                            return Ok(ExternalResult::Error(exc));
-                        }
+                        let exc = match elements.len() {
+                            2 if elements[1].is_map() => {
+                                decoder.decode_exception(name, elements[1])?
+                            }
+                            3 => {
+                                let exc_type = decoder.decode_exc_type_named(name, elements[1])?;
+                                let msg: String = elements[2].decode().map_err(|_| {
+                                    Error::input_error(
+                                        "exception message is not a string",
+                                        name.to_owned(),
+                                    )
+                                })?;
+                                MontyException::new(exc_type, Some(msg))
+                            }
+                            _ => {
+                                return Err(Error::input_error(
+                                    "expected {:error, type, message} or {:error, %ExMonty.Exception{}}",
+                                    name.to_owned(),
+                                )
+                                .into())
+                            }
+                        };
+                        return Ok(ExternalResult::Error(exc));
                     }
                     _ => {}
                 }
@@ -281,29 +578,6 @@ fn decode_external_result<'a>(env: Env<'a>, term: Term<'a>) -> NifResult<Externa
     }
 
     // If it's just a value, treat as return
-    let obj = types::decode_monty_object(env, term)?;
+    let obj = decoder.decode_named(env, name, term)?;
     Ok(ExternalResult::Return(obj))
 }
-
-fn parse_exc_type(s: &str) -> monty::ExcType {
-    // Try parsing both snake_case and PascalCase
-    use std::str::FromStr;
-    if let Ok(t) = monty::ExcType::from_str(s) {
-        return t;
-    }
-    // Try converting from snake_case to PascalCase
-    let pascal = s
-        .split('_')
-        .map(|word| {
-            let mut chars = word.chars();
-            match chars.next() {
-                Some(c) => {
-                    let upper: String = c.to_uppercase().collect();
-                    upper + &chars.collect::<String>()
-                }
-                None => String::new(),
-            }
-        })
-        .collect::<String>();
-    monty::ExcType::from_str(&pascal).unwrap_or(monty::ExcType::RuntimeError)
-}
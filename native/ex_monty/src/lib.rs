@@ -1,12 +1,30 @@
+mod background;
+mod cancel;
 mod error;
+mod handles;
 mod interactive;
+mod print;
 mod resources;
 mod serialization;
+mod session;
+mod std_structs;
+mod step;
+mod terms;
+mod tracker;
 mod types;
 
-use monty::{CollectStringPrint, LimitedTracker, ResourceLimits};
-use resources::RunnerResource;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc, Mutex};
+use std::thread;
+
+use handles::HandleTable;
+use monty::{CollectStringPrint, LimitedTracker, MontyObject, ResourceLimits};
+use resources::{ExecState, RunnerResource};
+use rustler::types::atom::Atom;
 use rustler::{Encoder, Env, NifResult, ResourceArc, Term};
+use step::StepOpts;
+use tracker::ExTracker;
+use types::{DecodeOpts, Decoder, EncodeOpts, Limits};
 
 #[rustler::nif(schedule = "DirtyCpu")]
 fn compile(
@@ -15,13 +33,15 @@ fn compile(
     input_names: Vec<String>,
     external_fns: Vec<String>,
 ) -> NifResult<ResourceArc<RunnerResource>> {
-    let input_names_for_resource = input_names.clone();
-    let runner = monty::MontyRun::new(code, &script_name, input_names, external_fns)
-        .map_err(error::monty_exception_to_rustler_error)?;
-    Ok(ResourceArc::new(RunnerResource::new(
-        runner,
-        input_names_for_resource,
-    )))
+    error::guard(|| {
+        let input_names_for_resource = input_names.clone();
+        let runner = monty::MontyRun::new(code, &script_name, input_names, external_fns)
+            .map_err(error::compile_error)?;
+        Ok(ResourceArc::new(RunnerResource::new(
+            runner,
+            input_names_for_resource,
+        )))
+    })
 }
 
 #[rustler::nif(schedule = "DirtyCpu")]
@@ -30,24 +50,233 @@ fn run<'a>(
     runner: ResourceArc<RunnerResource>,
     inputs: Vec<(String, Term<'a>)>,
     limits: Term<'a>,
+    opts: Term<'a>,
 ) -> NifResult<Term<'a>> {
-    let runner_ref = runner.runner();
-    let monty_inputs = types::decode_inputs(env, inputs, runner.input_names())?;
-    let resource_limits = types::decode_resource_limits(limits)?;
-    let tracker = LimitedTracker::new(resource_limits);
-    let mut print = CollectStringPrint::new();
-
-    let result = runner_ref
-        .run(monty_inputs, tracker, &mut print)
-        .map_err(error::monty_exception_to_rustler_error)?;
-
-    let output = print.into_output();
-    let result_term = types::encode_monty_object(env, &result);
-    let output_term = output.encode(env);
-    Ok(rustler::types::tuple::make_tuple(
-        env,
-        &[result_term, output_term],
-    ))
+    error::guard(|| {
+        let decode = DecodeOpts::decode(opts)?;
+        let opts = StepOpts::decode(env, opts)?;
+        let job = RunJob::decode(env, runner, inputs, limits, decode)?;
+        let mut elements = vec![rustler::types::atom::ok().encode(env)];
+        elements.extend(job.execute(env, opts)?);
+        Ok(rustler::types::tuple::make_tuple(env, &elements))
+    })
+}
+
+#[rustler::nif]
+fn run_async<'a>(
+    env: Env<'a>,
+    runner: ResourceArc<RunnerResource>,
+    inputs: Term<'a>,
+    limits: Term<'a>,
+    opts: Term<'a>,
+    reference: Term<'a>,
+) -> NifResult<Atom> {
+    error::guard(|| {
+        let reply = background::Reply::new(env, opts, reference)?;
+        let decode = DecodeOpts::decode(opts)?;
+        types::decode_resource_limits(limits)?;
+        let (inputs, limits) = (reply.save(inputs), reply.save(limits));
+        Ok(reply.spawn(move |env, opts| {
+            let inputs = inputs.load(env).decode()?;
+            let job = RunJob::decode(env, runner, inputs, limits.load(env), decode)?;
+            let mut elements = vec![rustler::types::atom::ok().encode(env)];
+            elements.extend(job.execute(env, opts)?);
+            Ok(rustler::types::tuple::make_tuple(env, &elements))
+        }))
+    })
+}
+
+/// A decoded `run` call, ready to execute on this thread or the pool.
+struct RunJob {
+    runner: ResourceArc<RunnerResource>,
+    inputs: Vec<MontyObject>,
+    limits: Limits,
+    handles: HandleTable,
+}
+
+impl RunJob {
+    fn decode<'a>(
+        env: Env<'a>,
+        runner: ResourceArc<RunnerResource>,
+        inputs: Vec<(String, Term<'a>)>,
+        limits: Term<'a>,
+        decode: DecodeOpts,
+    ) -> NifResult<Self> {
+        let mut handles = HandleTable::default();
+        let mut decoder = Decoder::new(decode, &mut handles);
+        let inputs = types::decode_inputs(env, inputs, runner.input_names(), &mut decoder)?;
+        let limits = types::decode_resource_limits(limits)?;
+        Ok(Self {
+            runner,
+            inputs,
+            limits,
+            handles,
+        })
+    }
+
+    /// Run to completion and return `[result, output]`, followed by the
+    /// usage map if `stats` was requested.
+    fn execute<'a>(self, env: Env<'a>, opts: StepOpts<'a>) -> NifResult<Vec<Term<'a>>> {
+        let RunJob {
+            runner,
+            inputs,
+            limits,
+            handles,
+        } = self;
+        let stats = opts.stats;
+        let encode = opts.encode;
+        let tracker = ExTracker::new(limits.resource);
+        let state = ExecState::new(limits.output, limits.budget, handles);
+
+        let (result, output, state) =
+            opts.run(state, |print| runner.runner().run(inputs, tracker, print));
+
+        let mut elements = vec![
+            types::encode_monty_object(env, &result?, &encode, &state.handles),
+            output.encode(env),
+        ];
+        if stats {
+            elements.push(tracker::encode_usage(env, &state.usage));
+        }
+        Ok(elements)
+    }
+}
+
+#[rustler::nif(schedule = "DirtyCpu")]
+fn run_batch<'a>(
+    env: Env<'a>,
+    runner: ResourceArc<RunnerResource>,
+    batch: Vec<Term<'a>>,
+    limits: Term<'a>,
+    opts: Term<'a>,
+) -> NifResult<Vec<Term<'a>>> {
+    error::guard(|| {
+        let limits = types::decode_resource_limits(limits)?;
+        let cancel = cancel::decode_cancel(opts)?;
+        let stats = types::get_flag(opts, "stats")?;
+        let encode = EncodeOpts::decode(opts)?;
+        let decode = DecodeOpts::decode(opts)?;
+        let max_concurrency = match types::get_option(opts, "max_concurrency")? {
+            Some(term) => term
+                .decode::<usize>()
+                .ok()
+                .filter(|&n| n > 0)
+                .ok_or_else(|| {
+                    error::Error::invalid_option(
+                        "max_concurrency",
+                        "max_concurrency must be a positive integer",
+                    )
+                })?,
+            None => 1,
+        };
+
+        // Inputs that fail to decode only fail their own item.
+        let mut results: Vec<Option<NifResult<Term<'a>>>> = Vec::with_capacity(batch.len());
+        let mut jobs = Vec::new();
+        for (index, item) in batch.into_iter().enumerate() {
+            let mut handles = HandleTable::default();
+            let mut decoder = Decoder::new(decode, &mut handles);
+            let inputs: NifResult<Vec<(String, Term)>> = item.decode().map_err(|_| {
+                let path = format!("batch[{index}]");
+                error::Error::input_error("expected a map of inputs", path).into()
+            });
+            match inputs.and_then(|inputs| {
+                types::decode_inputs(env, inputs, runner.input_names(), &mut decoder)
+            }) {
+                Ok(inputs) => {
+                    jobs.push((index, inputs, handles));
+                    results.push(None);
+                }
+                Err(err) => results.push(Some(Err(err))),
+            }
+        }
+
+        // A panicking item only fails itself, not the items sharing its thread.
+        let tracker = ExTracker::new(limits.resource);
+        let run_item = |inputs: Vec<MontyObject>, handles: HandleTable| {
+            panic::catch_unwind(AssertUnwindSafe(|| {
+                let mut budget = limits.budget.clone();
+                budget.restart();
+                let state = ExecState::new(limits.output.clone(), budget, handles);
+                step::run_step(None, cancel.clone(), state, |print| {
+                    runner.runner().run(inputs, tracker.clone(), print)
+                })
+            }))
+            .map_err(error::panic_error)
+        };
+
+        // More threads than CPUs would only add contention, so the requested
+        // concurrency is capped.
+        let cpus = thread::available_parallelism().map_or(1, |n| n.get());
+        let workers = max_concurrency.min(cpus).min(jobs.len());
+        let outcomes = if workers <= 1 {
+            jobs.into_iter()
+                .map(|(index, inputs, handles)| (index, run_item(inputs, handles)))
+                .collect()
+        } else {
+            let (sender, receiver) = mpsc::channel();
+            for job in jobs {
+                let _ = sender.send(job);
+            }
+            drop(sender);
+            let receiver = Mutex::new(receiver);
+
+            thread::scope(|scope| {
+                let threads: Vec<_> = (0..workers)
+                    .map(|_| {
+                        scope.spawn(|| {
+                            let mut done = Vec::new();
+                            loop {
+                                let job = resources::lock(&receiver).recv();
+                                let Ok((index, inputs, handles)) = job else {
+                                    return done;
+                                };
+                                done.push((index, run_item(inputs, handles)));
+                            }
+                        })
+                    })
+                    .collect();
+                threads
+                    .into_iter()
+                    .flat_map(|thread| thread.join().unwrap_or_default())
+                    .collect::<Vec<_>>()
+            })
+        };
+
+        for (index, outcome) in outcomes {
+            let (result, output, state) = match outcome {
+                Ok(outcome) => outcome.check(),
+                Err(err) => {
+                    results[index] = Some(Err(err.into()));
+                    continue;
+                }
+            };
+            results[index] = Some(result.map(|value| {
+                let mut elements = vec![
+                    rustler::types::atom::ok().encode(env),
+                    types::encode_monty_object(env, &value, &encode, &state.handles),
+                    output.encode(env),
+                ];
+                if stats {
+                    elements.push(tracker::encode_usage(env, &state.usage));
+                }
+                rustler::types::tuple::make_tuple(env, &elements)
+            }));
+        }
+
+        Ok(results
+            .into_iter()
+            .map(|result| {
+                let result = result.unwrap_or_else(|| {
+                    Err(error::Error::new("internal_error", "batch item has no result").into())
+                });
+                match result {
+                    Ok(term) => term,
+                    Err(err) => error::encode_error_tuple(env, err),
+                }
+            })
+            .collect())
+    })
 }
 
 #[rustler::nif(schedule = "DirtyCpu")]
@@ -56,22 +285,27 @@ fn run_no_limits<'a>(
     runner: ResourceArc<RunnerResource>,
     inputs: Vec<(String, Term<'a>)>,
 ) -> NifResult<Term<'a>> {
-    let runner_ref = runner.runner();
-    let monty_inputs = types::decode_inputs(env, inputs, runner.input_names())?;
-    let mut print = CollectStringPrint::new();
-    let tracker = LimitedTracker::new(ResourceLimits::new());
-
-    let result = runner_ref
-        .run(monty_inputs, tracker, &mut print)
-        .map_err(error::monty_exception_to_rustler_error)?;
-
-    let output = print.into_output();
-    let result_term = types::encode_monty_object(env, &result);
-    let output_term = output.encode(env);
-    Ok(rustler::types::tuple::make_tuple(
-        env,
-        &[result_term, output_term],
-    ))
+    error::guard(|| {
+        let runner_ref = runner.runner();
+        let mut handles = HandleTable::default();
+        let mut decoder = Decoder::new(DecodeOpts::default(), &mut handles);
+        let monty_inputs = types::decode_inputs(env, inputs, runner.input_names(), &mut decoder)?;
+        let mut print = CollectStringPrint::new();
+        let tracker = LimitedTracker::new(ResourceLimits::new());
+
+        let result = runner_ref
+            .run(monty_inputs, tracker, &mut print)
+            .map_err(error::monty_exception_to_rustler_error)?;
+
+        let output = print.into_output();
+        let result_term =
+            types::encode_monty_object(env, &result, &EncodeOpts::default(), &handles);
+        let output_term = output.encode(env);
+        Ok(rustler::types::tuple::make_tuple(
+            env,
+            &[result_term, output_term],
+        ))
+    })
 }
 
 rustler::init!("Elixir.ExMonty.Native");
@@ -0,0 +1,211 @@
+use std::borrow::Cow;
+
+use monty::{MontyException, PrintWriter};
+use rustler::types::tuple::get_tuple;
+use rustler::{Encoder, Env, LocalPid, NifResult, Term};
+
+use crate::error::Error;
+use crate::terms;
+
+/// Destination for streamed print output: `{:ex_monty_print, tag, chunk}` is
+/// sent to `pid` for every completed line.
+pub struct PrintTarget<'a> {
+    env: Env<'a>,
+    pid: LocalPid,
+    tag: Term<'a>,
+}
+
+impl<'a> PrintTarget<'a> {
+    fn send(&self, chunk: &str) {
+        let env = self.env;
+        let message = rustler::types::tuple::make_tuple(
+            env,
+            &[
+                terms::atom(env, "ex_monty_print"),
+                self.tag,
+                chunk.encode(env),
+            ],
+        );
+        // A dead or unreachable listener must not abort the script.
+        let _ = env.send(&self.pid, message);
+    }
+}
+
+/// Decode the `:print_to` option: `nil`, a pid (tagged `nil`) or `{pid, tag}`.
+pub fn decode_print_target<'a>(
+    env: Env<'a>,
+    term: Option<Term<'a>>,
+) -> NifResult<Option<PrintTarget<'a>>> {
+    let Some(term) = term else {
+        return Ok(None);
+    };
+
+    let invalid = || {
+        Error::invalid_option(
+            "print_to",
+            format!("invalid print_to: expected a pid or {{pid, ref}}, got: {term:?}"),
+        )
+    };
+    if let Ok(pid) = term.decode::<LocalPid>() {
+        let tag = terms::nil(env);
+        return Ok(Some(PrintTarget { env, pid, tag }));
+    }
+    let elements = get_tuple(term).map_err(|_| invalid())?;
+    let [pid, tag] = elements[..] else {
+        return Err(invalid().into());
+    };
+
+    let pid: LocalPid = pid.decode().map_err(|_| invalid())?;
+    Ok(Some(PrintTarget { env, pid, tag }))
+}
+
+/// What to do once a script has printed more than `max_output_bytes`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum OutputPolicy {
+    /// Stop execution with `{:output_limit, limit, written}`.
+    #[default]
+    Error,
+    /// Keep running, drop further output and append a truncation marker.
+    Truncate,
+}
+
+/// Appended once when output is cut off under `OutputPolicy::Truncate`.
+const TRUNCATION_MARKER: &str = "\n[output truncated]\n";
+
+/// Output cap for one execution. Carried with the snapshot between
+/// `start`/`resume` steps so the limit covers the execution as a whole.
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct OutputBudget {
+    max_bytes: Option<usize>,
+    policy: OutputPolicy,
+    /// Total bytes the script has tried to print, including dropped output.
+    written: usize,
+    truncated: bool,
+}
+
+impl OutputBudget {
+    pub fn new(max_bytes: Option<usize>, policy: OutputPolicy) -> Self {
+        Self {
+            max_bytes,
+            policy,
+            written: 0,
+            truncated: false,
+        }
+    }
+
+    /// Record `len` bytes of output and return how many of them fit.
+    fn admit(&mut self, len: usize) -> usize {
+        let remaining = match self.max_bytes {
+            Some(max) => max.saturating_sub(self.written),
+            None => usize::MAX,
+        };
+        self.written = self.written.saturating_add(len);
+        len.min(remaining)
+    }
+
+    /// Bytes that may still be printed, or `None` without a cap.
+    pub fn remaining(&self) -> Option<usize> {
+        self.max_bytes.map(|max| max.saturating_sub(self.written))
+    }
+
+    /// `Some((limit, written))` once the cap has been exceeded under `OutputPolicy::Error`.
+    pub fn exceeded(&self) -> Option<(usize, usize)> {
+        match self.max_bytes {
+            Some(max) if self.policy == OutputPolicy::Error && self.written > max => {
+                Some((max, self.written))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Print writer that collects output like `CollectStringPrint` and, when a
+/// target is set, also streams each completed line to it while the script runs.
+///
+/// Output is capped by an `OutputBudget`. Any trailing partial line is sent
+/// when the writer is dropped, so output written before an exception or a
+/// pause still reaches the listener.
+pub struct StreamPrint<'a> {
+    output: String,
+    /// Byte offset into `output` of the first character not yet streamed.
+    sent: usize,
+    target: Option<PrintTarget<'a>>,
+    budget: OutputBudget,
+}
+
+impl<'a> StreamPrint<'a> {
+    pub fn new(target: Option<PrintTarget<'a>>, budget: OutputBudget) -> Self {
+        Self {
+            output: String::new(),
+            sent: 0,
+            target,
+            budget,
+        }
+    }
+
+    /// Flush any unsent output and return it with the updated budget.
+    pub fn finish(mut self) -> (String, OutputBudget) {
+        self.flush();
+        (
+            std::mem::take(&mut self.output),
+            std::mem::take(&mut self.budget),
+        )
+    }
+
+    fn push(&mut self, s: &str) -> Result<(), MontyException> {
+        let allowed = self.budget.admit(s.len());
+        if allowed == s.len() {
+            self.output.push_str(s);
+            if s.contains('\n') {
+                self.flush();
+            }
+            return Ok(());
+        }
+
+        match self.budget.policy {
+            OutputPolicy::Error => Err(MontyException::new(
+                monty::ExcType::RuntimeError,
+                Some("output limit exceeded".to_owned()),
+            )),
+            OutputPolicy::Truncate => {
+                if !self.budget.truncated {
+                    let mut end = allowed;
+                    while !s.is_char_boundary(end) {
+                        end -= 1;
+                    }
+                    self.output.push_str(&s[..end]);
+                    self.output.push_str(TRUNCATION_MARKER);
+                    self.budget.truncated = true;
+                    self.flush();
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        if let Some(target) = &self.target {
+            if self.sent < self.output.len() {
+                target.send(&self.output[self.sent..]);
+            }
+        }
+        self.sent = self.output.len();
+    }
+}
+
+impl PrintWriter for StreamPrint<'_> {
+    fn stdout_write(&mut self, output: Cow<'_, str>) -> Result<(), MontyException> {
+        self.push(&output)
+    }
+
+    fn stdout_push(&mut self, end: char) -> Result<(), MontyException> {
+        let mut buf = [0u8; 4];
+        self.push(end.encode_utf8(&mut buf))
+    }
+}
+
+impl Drop for StreamPrint<'_> {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
@@ -1,6 +1,19 @@
-use monty::{FutureSnapshot, LimitedTracker, MontyRun, Snapshot};
-use rustler::Resource;
-use std::sync::Mutex;
+use monty::{FutureSnapshot, MontyRepl, MontyRun, ReplFutureSnapshot, ReplSnapshot, Snapshot};
+use rustler::{Env, LocalPid, Monitor, Resource, ResourceArc};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use crate::handles::HandleTable;
+use crate::print::OutputBudget;
+use crate::session::{self, PausedCell};
+use crate::tracker::{Budget, ExTracker, Usage};
+
+/// Lock `mutex`, recovering it if a thread panicked while holding it. Every
+/// value guarded here is replaced whole, never left half-updated, so a
+/// poisoned lock still holds a consistent value.
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
 
 /// Wrapper around MontyRun for use as a Rustler resource.
 /// MontyRun is Clone, so we can share it safely.
@@ -33,55 +46,336 @@ impl RunnerResource {
 #[rustler::resource_impl]
 impl Resource for RunnerResource {}
 
-/// Wrapper around Snapshot<LimitedTracker>.
+/// Execution state kept outside the interpreter that has to travel with a
+/// snapshot from one step to the next (and through dump/load).
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct ExecState {
+    pub output: OutputBudget,
+    pub budget: Budget,
+    pub usage: Usage,
+    pub handles: HandleTable,
+}
+
+impl ExecState {
+    /// State for a new execution: nothing used yet.
+    pub fn new(output: OutputBudget, budget: Budget, handles: HandleTable) -> Self {
+        Self {
+            output,
+            budget,
+            usage: Usage::default(),
+            handles,
+        }
+    }
+}
+
+/// Wrapper around Snapshot<ExTracker>.
 /// Uses Mutex<Option<...>> because Snapshot::run consumes self.
 pub struct SnapshotResource {
-    snapshot: Mutex<Option<Snapshot<LimitedTracker>>>,
+    snapshot: Mutex<Option<(Snapshot<ExTracker>, ExecState)>>,
 }
 
 impl SnapshotResource {
-    pub fn new(snapshot: Snapshot<LimitedTracker>) -> Self {
+    pub fn new(snapshot: Snapshot<ExTracker>, state: ExecState) -> Self {
         Self {
-            snapshot: Mutex::new(Some(snapshot)),
+            snapshot: Mutex::new(Some((snapshot, state))),
         }
     }
 
     /// Take the snapshot out, consuming it. Returns None if already taken.
-    pub fn take(&self) -> Option<Snapshot<LimitedTracker>> {
-        self.snapshot.lock().unwrap().take()
+    pub fn take(&self) -> Option<(Snapshot<ExTracker>, ExecState)> {
+        lock(&self.snapshot).take()
+    }
+
+    /// Access the execution state without consuming the snapshot.
+    pub fn with_state<F, R>(&self, f: F) -> Option<R>
+    where
+        F: FnOnce(&ExecState) -> R,
+    {
+        let guard = lock(&self.snapshot);
+        guard.as_ref().map(|(_, state)| f(state))
     }
 }
 
 #[rustler::resource_impl]
 impl Resource for SnapshotResource {}
 
-/// Wrapper around FutureSnapshot<LimitedTracker>.
+/// Wrapper around FutureSnapshot<ExTracker>.
 /// Uses Mutex<Option<...>> because FutureSnapshot::resume consumes self.
 pub struct FutureSnapshotResource {
-    snapshot: Mutex<Option<FutureSnapshot<LimitedTracker>>>,
+    snapshot: Mutex<Option<(FutureSnapshot<ExTracker>, ExecState)>>,
 }
 
 impl FutureSnapshotResource {
-    pub fn new(snapshot: FutureSnapshot<LimitedTracker>) -> Self {
+    pub fn new(snapshot: FutureSnapshot<ExTracker>, state: ExecState) -> Self {
         Self {
-            snapshot: Mutex::new(Some(snapshot)),
+            snapshot: Mutex::new(Some((snapshot, state))),
         }
     }
 
     /// Take the snapshot out, consuming it. Returns None if already taken.
-    pub fn take(&self) -> Option<FutureSnapshot<LimitedTracker>> {
-        self.snapshot.lock().unwrap().take()
+    pub fn take(&self) -> Option<(FutureSnapshot<ExTracker>, ExecState)> {
+        lock(&self.snapshot).take()
     }
 
     /// Access the snapshot without consuming it (for pending_call_ids).
     pub fn with<F, R>(&self, f: F) -> Option<R>
     where
-        F: FnOnce(&FutureSnapshot<LimitedTracker>) -> R,
+        F: FnOnce(&FutureSnapshot<ExTracker>) -> R,
     {
-        let guard = self.snapshot.lock().unwrap();
-        guard.as_ref().map(f)
+        let guard = lock(&self.snapshot);
+        guard.as_ref().map(|(snap, _)| f(snap))
+    }
+
+    /// Access the execution state without consuming the snapshot.
+    pub fn with_state<F, R>(&self, f: F) -> Option<R>
+    where
+        F: FnOnce(&ExecState) -> R,
+    {
+        let guard = lock(&self.snapshot);
+        guard.as_ref().map(|(_, state)| f(state))
     }
 }
 
 #[rustler::resource_impl]
 impl Resource for FutureSnapshotResource {}
+
+/// Limits of a REPL session, applied afresh to every cell.
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct SessionLimits {
+    pub output: OutputBudget,
+    pub budget: Budget,
+}
+
+/// What a session was created with, to start it over if a cell panics and
+/// takes the REPL down with it.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct SessionOrigin {
+    pub script_name: String,
+    pub external_fns: Vec<String>,
+    pub tracker: ExTracker,
+}
+
+impl SessionOrigin {
+    pub fn repl(&self) -> MontyRepl<ExTracker> {
+        MontyRepl::new(
+            &self.script_name,
+            self.external_fns.clone(),
+            self.tracker.clone(),
+        )
+    }
+}
+
+/// A REPL session whose globals persist from one cell to the next.
+/// The REPL is taken out while a cell runs or is paused, and put back when
+/// the cell completes or fails. Handles live as long as the session, since
+/// the REPL's globals can keep them from one cell to the next.
+pub struct SessionResource {
+    repl: Mutex<Option<MontyRepl<ExTracker>>>,
+    origin: SessionOrigin,
+    limits: SessionLimits,
+    handles: Mutex<HandleTable>,
+}
+
+impl SessionResource {
+    pub fn new(
+        repl: MontyRepl<ExTracker>,
+        origin: SessionOrigin,
+        limits: SessionLimits,
+        handles: HandleTable,
+    ) -> Self {
+        Self {
+            repl: Mutex::new(Some(repl)),
+            origin,
+            limits,
+            handles: Mutex::new(handles),
+        }
+    }
+
+    /// Take the REPL out to run a cell. Returns None while another cell is running.
+    pub fn take(&self) -> Option<MontyRepl<ExTracker>> {
+        lock(&self.repl).take()
+    }
+
+    /// Put the REPL back once its cell has finished.
+    pub fn restore(&self, repl: MontyRepl<ExTracker>) {
+        *lock(&self.repl) = Some(repl);
+    }
+
+    /// Start over with a new, empty REPL if a cell lost the current one.
+    /// A panic inside the interpreter leaves nothing to put back.
+    pub fn reset_if_lost(&self) {
+        let mut repl = lock(&self.repl);
+        if repl.is_none() {
+            *repl = Some(self.origin.repl());
+        }
+    }
+
+    /// Access the REPL without taking it (for dumping).
+    pub fn with<F, R>(&self, f: F) -> Option<R>
+    where
+        F: FnOnce(&MontyRepl<ExTracker>, &SessionOrigin, &SessionLimits, &HandleTable) -> R,
+    {
+        let guard = lock(&self.repl);
+        let handles = lock(&self.handles);
+        guard
+            .as_ref()
+            .map(|repl| f(repl, &self.origin, &self.limits, &handles))
+    }
+
+    /// Execution state for a new cell.
+    pub fn cell_state(&self) -> ExecState {
+        let mut budget = self.limits.budget.clone();
+        budget.restart();
+        let handles = lock(&self.handles).clone();
+        ExecState::new(self.limits.output.clone(), budget, handles)
+    }
+
+    /// Keep the handles a cell step has added for later cells.
+    pub fn save_handles(&self, handles: &HandleTable) {
+        *lock(&self.handles) = handles.clone();
+    }
+}
+
+#[rustler::resource_impl]
+impl Resource for SessionResource {}
+
+/// A session cell paused at an external function or OS call.
+/// Resuming it to completion returns the REPL to `session`; dropping it
+/// unresumed unwinds the cell (see `session::abandon_cell`).
+pub struct SessionSnapshotResource {
+    snapshot: Mutex<Option<(ReplSnapshot<ExTracker>, ExecState)>>,
+    session: ResourceArc<SessionResource>,
+}
+
+impl SessionSnapshotResource {
+    pub fn new(
+        snapshot: ReplSnapshot<ExTracker>,
+        state: ExecState,
+        session: ResourceArc<SessionResource>,
+    ) -> Self {
+        Self {
+            snapshot: Mutex::new(Some((snapshot, state))),
+            session,
+        }
+    }
+
+    /// Take the snapshot out, consuming it. Returns None if already taken.
+    pub fn take(&self) -> Option<(ReplSnapshot<ExTracker>, ExecState)> {
+        lock(&self.snapshot).take()
+    }
+
+    pub fn session(&self) -> ResourceArc<SessionResource> {
+        self.session.clone()
+    }
+
+    /// Access the execution state without consuming the snapshot.
+    pub fn with_state<F, R>(&self, f: F) -> Option<R>
+    where
+        F: FnOnce(&ExecState) -> R,
+    {
+        let guard = lock(&self.snapshot);
+        guard.as_ref().map(|(_, state)| f(state))
+    }
+}
+
+#[rustler::resource_impl]
+impl Resource for SessionSnapshotResource {}
+
+impl Drop for SessionSnapshotResource {
+    fn drop(&mut self) {
+        let paused = lock(&self.snapshot).take();
+        if let Some((snapshot, state)) = paused {
+            session::abandon_cell(&self.session, PausedCell::Call(snapshot), state);
+        }
+    }
+}
+
+/// A session cell waiting for futures to resolve. Like
+/// `SessionSnapshotResource`, dropping it unresumed unwinds the cell.
+pub struct SessionFutureSnapshotResource {
+    snapshot: Mutex<Option<(ReplFutureSnapshot<ExTracker>, ExecState)>>,
+    session: ResourceArc<SessionResource>,
+}
+
+impl SessionFutureSnapshotResource {
+    pub fn new(
+        snapshot: ReplFutureSnapshot<ExTracker>,
+        state: ExecState,
+        session: ResourceArc<SessionResource>,
+    ) -> Self {
+        Self {
+            snapshot: Mutex::new(Some((snapshot, state))),
+            session,
+        }
+    }
+
+    /// Take the snapshot out, consuming it. Returns None if already taken.
+    pub fn take(&self) -> Option<(ReplFutureSnapshot<ExTracker>, ExecState)> {
+        lock(&self.snapshot).take()
+    }
+
+    pub fn session(&self) -> ResourceArc<SessionResource> {
+        self.session.clone()
+    }
+
+    /// Access the snapshot without consuming it (for pending_call_ids).
+    pub fn with<F, R>(&self, f: F) -> Option<R>
+    where
+        F: FnOnce(&ReplFutureSnapshot<ExTracker>) -> R,
+    {
+        let guard = lock(&self.snapshot);
+        guard.as_ref().map(|(snap, _)| f(snap))
+    }
+
+    /// Access the execution state without consuming the snapshot.
+    pub fn with_state<F, R>(&self, f: F) -> Option<R>
+    where
+        F: FnOnce(&ExecState) -> R,
+    {
+        let guard = lock(&self.snapshot);
+        guard.as_ref().map(|(_, state)| f(state))
+    }
+}
+
+#[rustler::resource_impl]
+impl Resource for SessionFutureSnapshotResource {}
+
+impl Drop for SessionFutureSnapshotResource {
+    fn drop(&mut self) {
+        let paused = lock(&self.snapshot).take();
+        if let Some((snapshot, state)) = paused {
+            session::abandon_cell(&self.session, PausedCell::Futures(snapshot), state);
+        }
+    }
+}
+
+/// Cancellation token shared between Elixir and the executions it is passed to.
+/// The flag is checked by `ExTracker` at every time and allocation check.
+#[derive(Default)]
+pub struct CancelTokenResource {
+    flag: Arc<AtomicBool>,
+}
+
+impl CancelTokenResource {
+    pub fn cancel(&self) {
+        self.flag.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.flag.load(Ordering::Relaxed)
+    }
+
+    pub fn flag(&self) -> Arc<AtomicBool> {
+        self.flag.clone()
+    }
+}
+
+#[rustler::resource_impl]
+impl Resource for CancelTokenResource {
+    const IMPLEMENTS_DOWN: bool = true;
+
+    /// The owning process died: nobody is left to collect the result.
+    fn down<'a>(&'a self, _env: Env<'a>, _pid: LocalPid, _monitor: Monitor) {
+        self.cancel();
+    }
+}
@@ -1,7 +1,26 @@
 use monty::{LimitedTracker, MontyRun};
 use rustler::{Binary, Env, NifResult, OwnedBinary, ResourceArc};
 
-use crate::resources::{FutureSnapshotResource, RunnerResource, SnapshotResource};
+use crate::error::{self, Detail, Error};
+use crate::handles::HandleTable;
+use crate::resources::{
+    ExecState, FutureSnapshotResource, RunnerResource, SessionLimits, SessionOrigin,
+    SessionResource, SnapshotResource,
+};
+use crate::tracker::ExTracker;
+
+/// Every dump starts with `DUMP_MAGIC` and a version byte. The version is
+/// bumped whenever the layout of any dump changes, so an old or foreign dump
+/// is reported as such instead of failing to decode halfway.
+const DUMP_MAGIC: &[u8] = b"ExMonty";
+const DUMP_VERSION: u8 = 1;
+
+type SessionDump = (
+    monty::MontyRepl<ExTracker>,
+    SessionOrigin,
+    SessionLimits,
+    HandleTable,
+);
 
 #[derive(serde::Serialize, serde::Deserialize)]
 struct RunnerDump {
@@ -11,49 +30,53 @@ struct RunnerDump {
 
 #[rustler::nif(schedule = "DirtyCpu")]
 fn dump_runner(env: Env, runner: ResourceArc<RunnerResource>) -> NifResult<Binary> {
-    let dump = RunnerDump {
-        runner: runner.runner().clone(),
-        input_names: runner.input_names().to_vec(),
-    };
-
-    let bytes = postcard::to_allocvec(&dump)
-        .map_err(|e| rustler::Error::RaiseTerm(Box::new(format!("serialization error: {e}"))))?;
-    let mut binary = OwnedBinary::new(bytes.len())
-        .ok_or_else(|| rustler::Error::RaiseTerm(Box::new("failed to allocate binary")))?;
-    binary.as_mut_slice().copy_from_slice(&bytes);
-    Ok(binary.release(env))
+    error::guard(|| {
+        let dump = RunnerDump {
+            runner: runner.runner().clone(),
+            input_names: runner.input_names().to_vec(),
+        };
+
+        encode_dump(env, &dump)
+    })
 }
 
 #[rustler::nif(schedule = "DirtyCpu")]
 fn load_runner(binary: Binary) -> NifResult<ResourceArc<RunnerResource>> {
-    let dump: RunnerDump = postcard::from_bytes(binary.as_slice())
-        .map_err(|e| rustler::Error::RaiseTerm(Box::new(format!("deserialization error: {e}"))))?;
-    Ok(ResourceArc::new(RunnerResource::new(
-        dump.runner,
-        dump.input_names,
-    )))
+    error::guard(|| {
+        // Runner dumps have not changed since dumps were untagged, so an
+        // untagged one is still read as is.
+        let dump: RunnerDump = if binary.as_slice().starts_with(DUMP_MAGIC) {
+            decode_dump(binary.as_slice(), |_| false)?
+        } else {
+            postcard::from_bytes(binary.as_slice()).map_err(invalid_snapshot)?
+        };
+        Ok(ResourceArc::new(RunnerResource::new(
+            dump.runner,
+            dump.input_names,
+        )))
+    })
 }
 
 #[rustler::nif(schedule = "DirtyCpu")]
 fn dump_snapshot(env: Env, snapshot: ResourceArc<SnapshotResource>) -> NifResult<Binary> {
-    let snap = snapshot
-        .take()
-        .ok_or_else(|| rustler::Error::RaiseTerm(Box::new("snapshot already consumed")))?;
+    error::guard(|| {
+        let snap = snapshot
+            .take()
+            .ok_or_else(|| Error::consumed_snapshot("snapshot"))?;
 
-    let bytes = postcard::to_allocvec(&snap)
-        .map_err(|e| rustler::Error::RaiseTerm(Box::new(format!("serialization error: {e}"))))?;
-
-    let mut binary = OwnedBinary::new(bytes.len())
-        .ok_or_else(|| rustler::Error::RaiseTerm(Box::new("failed to allocate binary")))?;
-    binary.as_mut_slice().copy_from_slice(&bytes);
-    Ok(binary.release(env))
+        encode_dump(env, &snap)
+    })
 }
 
 #[rustler::nif(schedule = "DirtyCpu")]
 fn load_snapshot(binary: Binary) -> NifResult<ResourceArc<SnapshotResource>> {
-    let snap: monty::Snapshot<LimitedTracker> = postcard::from_bytes(binary.as_slice())
-        .map_err(|e| rustler::Error::RaiseTerm(Box::new(format!("deserialization error: {e}"))))?;
-    Ok(ResourceArc::new(SnapshotResource::new(snap)))
+    error::guard(|| {
+        let (snap, state): (monty::Snapshot<ExTracker>, ExecState) = decode_dump(
+            binary.as_slice(),
+            is_untagged::<monty::Snapshot<LimitedTracker>>,
+        )?;
+        Ok(ResourceArc::new(SnapshotResource::new(snap, state)))
+    })
 }
 
 #[rustler::nif(schedule = "DirtyCpu")]
@@ -61,22 +84,99 @@ fn dump_future_snapshot(
     env: Env,
     futures: ResourceArc<FutureSnapshotResource>,
 ) -> NifResult<Binary> {
-    let snap = futures
-        .take()
-        .ok_or_else(|| rustler::Error::RaiseTerm(Box::new("future snapshot already consumed")))?;
-
-    let bytes = postcard::to_allocvec(&snap)
-        .map_err(|e| rustler::Error::RaiseTerm(Box::new(format!("serialization error: {e}"))))?;
+    error::guard(|| {
+        let snap = futures
+            .take()
+            .ok_or_else(|| Error::consumed_snapshot("future snapshot"))?;
 
-    let mut binary = OwnedBinary::new(bytes.len())
-        .ok_or_else(|| rustler::Error::RaiseTerm(Box::new("failed to allocate binary")))?;
-    binary.as_mut_slice().copy_from_slice(&bytes);
-    Ok(binary.release(env))
+        encode_dump(env, &snap)
+    })
 }
 
 #[rustler::nif(schedule = "DirtyCpu")]
 fn load_future_snapshot(binary: Binary) -> NifResult<ResourceArc<FutureSnapshotResource>> {
-    let snap: monty::FutureSnapshot<LimitedTracker> = postcard::from_bytes(binary.as_slice())
-        .map_err(|e| rustler::Error::RaiseTerm(Box::new(format!("deserialization error: {e}"))))?;
-    Ok(ResourceArc::new(FutureSnapshotResource::new(snap)))
+    error::guard(|| {
+        let (snap, state): (monty::FutureSnapshot<ExTracker>, ExecState) = decode_dump(
+            binary.as_slice(),
+            is_untagged::<monty::FutureSnapshot<LimitedTracker>>,
+        )?;
+        Ok(ResourceArc::new(FutureSnapshotResource::new(snap, state)))
+    })
+}
+
+#[rustler::nif(schedule = "DirtyCpu")]
+fn dump_session(env: Env, session: ResourceArc<SessionResource>) -> NifResult<Binary> {
+    error::guard(|| {
+        session
+            .with(|repl, origin, limits, handles| {
+                encode_dump(env, &(repl, origin, limits, handles))
+            })
+            .ok_or_else(Error::session_busy)?
+    })
+}
+
+#[rustler::nif(schedule = "DirtyCpu")]
+fn load_session(binary: Binary) -> NifResult<ResourceArc<SessionResource>> {
+    error::guard(|| {
+        let (repl, origin, limits, handles): SessionDump =
+            decode_dump(binary.as_slice(), |_| false)?;
+        Ok(ResourceArc::new(SessionResource::new(
+            repl, origin, limits, handles,
+        )))
+    })
+}
+
+fn serialization_error(err: postcard::Error) -> rustler::Error {
+    Error::new("serialization_error", format!("serialization error: {err}")).into()
+}
+
+/// A binary that is not a dump of the expected kind, or was written by an
+/// incompatible version.
+fn invalid_snapshot(err: postcard::Error) -> rustler::Error {
+    Error::new("invalid_snapshot", format!("deserialization error: {err}")).into()
+}
+
+/// A dump with a version this build cannot read. Untagged dumps, written
+/// before dumps had a version, count as version 0.
+fn unsupported_version(version: u8) -> rustler::Error {
+    Error::new(
+        "unsupported_snapshot_version",
+        format!("unsupported snapshot version {version}, expected version {DUMP_VERSION}"),
+    )
+    .detail("version", Detail::Integer(version.into()))
+    .into()
+}
+
+/// Whether `bytes` is an untagged dump of a `T`, as written before dumps had
+/// a version.
+fn is_untagged<T: for<'de> serde::Deserialize<'de>>(bytes: &[u8]) -> bool {
+    matches!(postcard::take_from_bytes::<T>(bytes), Ok((_, rest)) if rest.is_empty())
+}
+
+/// Serialize `value` behind the magic and version header.
+fn encode_dump<'a, T: serde::Serialize>(env: Env<'a>, value: &T) -> NifResult<Binary<'a>> {
+    let bytes = postcard::to_allocvec(value).map_err(serialization_error)?;
+    let header = DUMP_MAGIC.len() + 1;
+    let mut binary = OwnedBinary::new(header + bytes.len())
+        .ok_or_else(|| Error::new("out_of_memory", "failed to allocate binary"))?;
+    let slice = binary.as_mut_slice();
+    slice[..DUMP_MAGIC.len()].copy_from_slice(DUMP_MAGIC);
+    slice[DUMP_MAGIC.len()] = DUMP_VERSION;
+    slice[header..].copy_from_slice(&bytes);
+    Ok(binary.release(env))
+}
+
+/// Check the header of a dump and deserialize the rest. `untagged` says
+/// whether a binary without a header is a dump from before versioning, so it
+/// can be told apart from a binary that is not a dump at all.
+fn decode_dump<'de, T: serde::Deserialize<'de>>(
+    bytes: &'de [u8],
+    untagged: impl FnOnce(&[u8]) -> bool,
+) -> NifResult<T> {
+    match bytes.strip_prefix(DUMP_MAGIC) {
+        Some([DUMP_VERSION, body @ ..]) => postcard::from_bytes(body).map_err(invalid_snapshot),
+        Some([version, ..]) => Err(unsupported_version(*version)),
+        _ if untagged(bytes) => Err(unsupported_version(0)),
+        _ => Err(Error::new("invalid_snapshot", "not an ExMonty dump").into()),
+    }
 }
@@ -0,0 +1,267 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
+use monty::{
+    ExcType, ExternalResult, MontyException, MontyObject, ReplError, ReplFutureSnapshot,
+    ReplProgress, ReplSnapshot,
+};
+use rustler::{Encoder, Env, NifResult, ResourceArc, Term};
+
+use crate::error;
+use crate::handles::HandleTable;
+use crate::interactive;
+use crate::resources::{
+    ExecState, SessionFutureSnapshotResource, SessionLimits, SessionOrigin, SessionResource,
+    SessionSnapshotResource,
+};
+use crate::step::{self, StepOpts};
+use crate::terms;
+use crate::tracker::{self, ExTracker};
+use crate::types::{self, EncodeOpts};
+
+#[rustler::nif]
+fn session_new(
+    script_name: String,
+    external_fns: Vec<String>,
+    limits: Term,
+) -> NifResult<ResourceArc<SessionResource>> {
+    error::guard(|| {
+        let limits = types::decode_resource_limits(limits)?;
+        let origin = SessionOrigin {
+            script_name,
+            external_fns,
+            tracker: ExTracker::new(limits.resource),
+        };
+        let limits = SessionLimits {
+            output: limits.output,
+            budget: limits.budget,
+        };
+        Ok(ResourceArc::new(SessionResource::new(
+            origin.repl(),
+            origin,
+            limits,
+            HandleTable::default(),
+        )))
+    })
+}
+
+#[rustler::nif(schedule = "DirtyCpu")]
+fn session_feed<'a>(
+    env: Env<'a>,
+    session: ResourceArc<SessionResource>,
+    code: String,
+    opts: Term<'a>,
+) -> NifResult<Term<'a>> {
+    error::guard(|| {
+        let opts = StepOpts::decode(env, opts)?;
+        let stats = opts.stats;
+        let encode = opts.encode;
+        let repl = session.take().ok_or_else(error::Error::session_busy)?;
+        let _reset = ResetOnPanic(&session);
+
+        let (progress, output, state) = opts.run(session.cell_state(), |print| {
+            settle(&session, repl.feed_start(code, print))
+        });
+        encode_cell_progress(env, progress, &session, state, &output, stats, &encode)
+    })
+}
+
+/// Starts `session` over if a cell step panics, so the panic is reported as
+/// `:internal_error` without leaving the session busy. The interpreter's
+/// state cannot be trusted after a panic, so the session's globals are lost.
+pub struct ResetOnPanic<'s>(pub &'s SessionResource);
+
+impl Drop for ResetOnPanic<'_> {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            self.0.reset_if_lost();
+        }
+    }
+}
+
+/// A paused cell taken out of its snapshot resource.
+pub enum PausedCell {
+    Call(ReplSnapshot<ExTracker>),
+    Futures(ReplFutureSnapshot<ExTracker>),
+}
+
+/// Unwind a paused cell that will never be resumed, so its REPL goes back
+/// to `session`. Called when the cell's snapshot is dropped unresumed.
+/// Nothing is reported: the cell's owner has already let it go.
+pub fn abandon_cell(session: &SessionResource, cell: PausedCell, state: ExecState) {
+    // This runs in a resource destructor, which must not unwind into the VM.
+    let _ = panic::catch_unwind(AssertUnwindSafe(|| unwind_cell(session, cell, state)));
+}
+
+/// Fail the cell's pending calls with a `RuntimeError` under a cancelled
+/// token, so the cell stops at its next check whatever its `except` clauses
+/// do, and fail it again if it manages to pause before that.
+fn unwind_cell(session: &SessionResource, mut cell: PausedCell, mut state: ExecState) {
+    let abandoned = || {
+        ExternalResult::Error(MontyException::new(
+            ExcType::RuntimeError,
+            Some("session cell abandoned".to_owned()),
+        ))
+    };
+    let cancel = Arc::new(AtomicBool::new(true));
+    let _reset = ResetOnPanic(session);
+
+    loop {
+        let outcome = step::run_step(None, Some(cancel.clone()), state, |print| {
+            let result = match cell {
+                PausedCell::Call(snapshot) => snapshot.run(abandoned(), print),
+                PausedCell::Futures(snapshot) => {
+                    let results = snapshot
+                        .pending_call_ids()
+                        .iter()
+                        .map(|&id| (id, abandoned()))
+                        .collect();
+                    snapshot.resume(results, print)
+                }
+            };
+            settle(session, result)
+        });
+        let (progress, _, next_state) = outcome.check();
+        state = next_state;
+        cell = match progress {
+            Ok(CellProgress::Paused(ReplProgress::FunctionCall {
+                state: snapshot, ..
+            }))
+            | Ok(CellProgress::Paused(ReplProgress::OsCall {
+                state: snapshot, ..
+            })) => PausedCell::Call(snapshot),
+            Ok(CellProgress::Paused(ReplProgress::ResolveFutures(snapshot))) => {
+                PausedCell::Futures(snapshot)
+            }
+            Ok(CellProgress::Paused(ReplProgress::Complete { repl, .. })) => {
+                session.restore(repl);
+                return;
+            }
+            Ok(CellProgress::Complete(_)) | Err(_) => return,
+        };
+    }
+}
+
+/// Progress of a session cell. A completed cell has already returned its
+/// REPL to the session.
+pub enum CellProgress {
+    Paused(ReplProgress<ExTracker>),
+    Complete(MontyObject),
+}
+
+/// Return the REPL to `session` as soon as the cell completes or fails, so
+/// the session stays usable whatever happens to the step result afterwards.
+pub fn settle(
+    session: &SessionResource,
+    result: Result<ReplProgress<ExTracker>, ReplError<ExTracker>>,
+) -> Result<CellProgress, MontyException> {
+    match result {
+        Ok(ReplProgress::Complete { repl, value }) => {
+            session.restore(repl);
+            Ok(CellProgress::Complete(value))
+        }
+        Ok(paused) => Ok(CellProgress::Paused(paused)),
+        Err(ReplError { repl, error }) => {
+            session.restore(repl);
+            Err(error)
+        }
+    }
+}
+
+/// Encode cell progress with the same tuples as `start`; paused cells get
+/// session snapshots that `resume`/`resume_futures` accept.
+///
+/// Handles added by the step are kept in the session even if the step
+/// failed, since the REPL's globals may already refer to them.
+pub fn encode_cell_progress<'a>(
+    env: Env<'a>,
+    progress: NifResult<CellProgress>,
+    session: &ResourceArc<SessionResource>,
+    exec_state: ExecState,
+    output: &str,
+    stats: bool,
+    encode: &EncodeOpts,
+) -> NifResult<Term<'a>> {
+    session.save_handles(&exec_state.handles);
+    let progress = progress?;
+
+    let output_term = output.encode(env);
+    let stats_term = stats.then(|| tracker::encode_usage(env, &exec_state.usage));
+    let complete = |value: &MontyObject| {
+        let tag = terms::atom(env, "complete");
+        vec![
+            tag,
+            types::encode_monty_object(env, value, encode, &exec_state.handles),
+            output_term,
+        ]
+    };
+
+    let mut elements = match progress {
+        CellProgress::Complete(value) => complete(&value),
+        CellProgress::Paused(ReplProgress::Complete { repl, value }) => {
+            session.restore(repl);
+            complete(&value)
+        }
+        CellProgress::Paused(ReplProgress::FunctionCall {
+            function_name,
+            args,
+            kwargs,
+            call_id,
+            state,
+        }) => {
+            let tag = terms::atom(env, "function_call");
+            let call = interactive::encode_function_call(
+                env,
+                &function_name,
+                &args,
+                &kwargs,
+                call_id,
+                encode,
+                &exec_state.handles,
+            );
+            let snapshot_ref = ResourceArc::new(SessionSnapshotResource::new(
+                state,
+                exec_state,
+                session.clone(),
+            ));
+            vec![tag, call, snapshot_ref.encode(env), output_term]
+        }
+        CellProgress::Paused(ReplProgress::OsCall {
+            function,
+            args,
+            kwargs,
+            call_id,
+            state,
+        }) => {
+            let tag = terms::atom(env, "os_call");
+            let call = interactive::encode_os_call(
+                env,
+                &function,
+                &args,
+                &kwargs,
+                call_id,
+                encode,
+                &exec_state.handles,
+            );
+            let snapshot_ref = ResourceArc::new(SessionSnapshotResource::new(
+                state,
+                exec_state,
+                session.clone(),
+            ));
+            vec![tag, call, snapshot_ref.encode(env), output_term]
+        }
+        CellProgress::Paused(ReplProgress::ResolveFutures(future_snapshot)) => {
+            let tag = terms::atom(env, "resolve_futures");
+            let futures_ref = ResourceArc::new(SessionFutureSnapshotResource::new(
+                future_snapshot,
+                exec_state,
+                session.clone(),
+            ));
+            vec![tag, futures_ref.encode(env), output_term]
+        }
+    };
+
+    elements.extend(stats_term);
+    Ok(rustler::types::tuple::make_tuple(env, &elements))
+}
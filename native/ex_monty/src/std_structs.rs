@@ -0,0 +1,193 @@
+use monty::MontyObject;
+use num_bigint::BigInt;
+use rustler::types::tuple::get_tuple;
+use rustler::{Encoder, Env, Term};
+
+use crate::terms;
+
+/// How an Elixir struct with no Python equivalent is passed to Python: as a
+/// named tuple whose type name is the struct module and whose fields are the
+/// struct keys, in this order.
+///
+/// `microsecond: {value, precision}` becomes the two fields `microsecond` and
+/// `precision`, and `calendar` is left out: only `Calendar.ISO` is supported.
+/// A named tuple with exactly this type name and these fields is encoded back
+/// as the struct.
+struct Layout {
+    module: &'static str,
+    fields: &'static [&'static str],
+}
+
+const LAYOUTS: &[Layout] = &[
+    Layout {
+        module: "Date",
+        fields: &["year", "month", "day"],
+    },
+    Layout {
+        module: "Time",
+        fields: &["hour", "minute", "second", "microsecond", "precision"],
+    },
+    Layout {
+        module: "NaiveDateTime",
+        fields: &[
+            "year",
+            "month",
+            "day",
+            "hour",
+            "minute",
+            "second",
+            "microsecond",
+            "precision",
+        ],
+    },
+    Layout {
+        module: "DateTime",
+        fields: &[
+            "year",
+            "month",
+            "day",
+            "hour",
+            "minute",
+            "second",
+            "microsecond",
+            "precision",
+            "time_zone",
+            "zone_abbr",
+            "utc_offset",
+            "std_offset",
+        ],
+    },
+    Layout {
+        module: "Decimal",
+        fields: &["sign", "coef", "exp"],
+    },
+];
+
+impl Layout {
+    fn has_calendar(&self) -> bool {
+        self.fields.contains(&"microsecond")
+    }
+}
+
+/// Decode a `Date`, `Time`, `NaiveDateTime`, `DateTime` or `Decimal` struct
+/// into a named tuple. Returns `None` for any other struct, and an error
+/// message for a struct that does not have the expected fields.
+pub fn decode(struct_name: &str, term: Term) -> Option<Result<MontyObject, String>> {
+    let module = struct_name.strip_prefix("Elixir.")?;
+    let layout = LAYOUTS.iter().find(|l| l.module == module)?;
+    Some(decode_layout(layout, term))
+}
+
+fn decode_layout(layout: &Layout, term: Term) -> Result<MontyObject, String> {
+    let get = |key: &str| {
+        terms::map_get(term, key).ok_or_else(|| format!("missing {key} in %{}{{}}", layout.module))
+    };
+
+    if layout.has_calendar() {
+        let calendar = get("calendar")?.atom_to_string().unwrap_or_default();
+        if calendar != "Elixir.Calendar.ISO" {
+            return Err(format!(
+                "unsupported calendar {calendar} in %{}{{}}",
+                layout.module
+            ));
+        }
+    }
+
+    let mut values = Vec::with_capacity(layout.fields.len());
+    for field in layout.fields {
+        let value = match *field {
+            "microsecond" | "precision" => {
+                let index = usize::from(*field == "precision");
+                get_tuple(get("microsecond")?)
+                    .ok()
+                    .filter(|elems| elems.len() == 2)
+                    .map(|elems| elems[index])
+            }
+            _ => Some(get(field)?),
+        };
+        let value = value
+            .and_then(plain_value)
+            .ok_or_else(|| format!("invalid {field} in %{}{{}}", layout.module))?;
+        values.push(value);
+    }
+
+    Ok(MontyObject::NamedTuple {
+        type_name: layout.module.to_owned(),
+        field_names: layout.fields.iter().map(|f| (*f).to_owned()).collect(),
+        values,
+    })
+}
+
+/// Field values are integers, strings, or atoms such as Decimal's `:NaN` and
+/// `:inf` coefficients, which Python sees as strings.
+fn plain_value(term: Term) -> Option<MontyObject> {
+    if let Ok(i) = term.decode::<i64>() {
+        return Some(MontyObject::Int(i));
+    }
+    if let Ok(bi) = term.decode::<BigInt>() {
+        return Some(MontyObject::BigInt(bi));
+    }
+    if let Ok(s) = term.decode::<String>() {
+        return Some(MontyObject::String(s));
+    }
+    match term.atom_to_string() {
+        Ok(name) if !matches!(name.as_str(), "nil" | "true" | "false") => {
+            Some(MontyObject::String(name))
+        }
+        _ => None,
+    }
+}
+
+/// Encode a named tuple produced by `decode` back into its struct. Returns
+/// `None` for any other named tuple, or if a field holds a value the struct
+/// cannot.
+pub fn encode<'a>(
+    env: Env<'a>,
+    type_name: &str,
+    field_names: &[String],
+    values: &[MontyObject],
+) -> Option<Term<'a>> {
+    let layout = LAYOUTS.iter().find(|l| {
+        l.module == type_name
+            && l.fields.len() == field_names.len()
+            && l.fields.iter().zip(field_names).all(|(a, b)| a == b)
+    })?;
+
+    let mut fields = Vec::with_capacity(layout.fields.len());
+    for (field, value) in layout.fields.iter().zip(values) {
+        let value = match *field {
+            "precision" => continue,
+            "microsecond" => {
+                let precision = layout
+                    .fields
+                    .iter()
+                    .position(|f| *f == "precision")
+                    .and_then(|i| values.get(i))?;
+                rustler::types::tuple::make_tuple(
+                    env,
+                    &[encode_plain(env, value)?, encode_plain(env, precision)?],
+                )
+            }
+            "coef" => match value {
+                MontyObject::String(s) if s == "NaN" || s == "inf" => terms::atom(env, s),
+                _ => encode_plain(env, value)?,
+            },
+            _ => encode_plain(env, value)?,
+        };
+        fields.push((*field, value));
+    }
+
+    if layout.has_calendar() {
+        fields.push(("calendar", terms::atom(env, "Elixir.Calendar.ISO")));
+    }
+    Some(terms::make_struct(env, layout.module, &fields))
+}
+
+fn encode_plain<'a>(env: Env<'a>, obj: &MontyObject) -> Option<Term<'a>> {
+    match obj {
+        MontyObject::Int(i) => Some(i.encode(env)),
+        MontyObject::BigInt(bi) => Some(bi.encode(env)),
+        MontyObject::String(s) => Some(s.encode(env)),
+        _ => None,
+    }
+}
@@ -0,0 +1,94 @@
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
+use monty::MontyException;
+use rustler::{Env, NifResult, Term};
+
+use crate::cancel;
+use crate::error;
+use crate::print::{self, PrintTarget, StreamPrint};
+use crate::resources::ExecState;
+use crate::tracker::{self, StepReport};
+use crate::types::{self, EncodeOpts};
+
+/// Per-call options shared by every NIF that runs the interpreter.
+pub struct StepOpts<'a> {
+    print_target: Option<PrintTarget<'a>>,
+    cancel: Option<Arc<AtomicBool>>,
+    /// Whether to report resource usage with the result.
+    pub stats: bool,
+    pub encode: EncodeOpts,
+}
+
+impl<'a> StepOpts<'a> {
+    pub fn decode(env: Env<'a>, opts: Term<'a>) -> NifResult<Self> {
+        Ok(Self {
+            print_target: print::decode_print_target(env, types::get_option(opts, "print_to")?)?,
+            cancel: cancel::decode_cancel(opts)?,
+            stats: types::get_flag(opts, "stats")?,
+            encode: EncodeOpts::decode(opts)?,
+        })
+    }
+
+    /// Run one interpreter step under the limits in `state`.
+    ///
+    /// Returns the step's result, the output it printed and the state to
+    /// carry into the next step.
+    pub fn run<T>(
+        self,
+        state: ExecState,
+        step: impl FnOnce(&mut StreamPrint<'a>) -> Result<T, MontyException>,
+    ) -> (NifResult<T>, String, ExecState) {
+        run_step(self.print_target, self.cancel, state, step).check()
+    }
+}
+
+/// What an interpreter step left behind, before it is turned into a NIF
+/// result. Unlike a `NifResult`, this can be sent across threads.
+pub struct StepOutcome<T> {
+    result: Result<T, MontyException>,
+    report: StepReport,
+    pub output: String,
+    pub state: ExecState,
+}
+
+impl<T> StepOutcome<T> {
+    /// Split into the NIF result (see `error::check_step`), the printed
+    /// output and the state for the next step.
+    pub fn check(self) -> (NifResult<T>, String, ExecState) {
+        let result = error::check_step(self.result, self.report, &self.state.output);
+        (result, self.output, self.state)
+    }
+}
+
+/// Run one interpreter step with the output, limits and cancellation flag of
+/// an execution.
+pub fn run_step<'a, T>(
+    print_target: Option<PrintTarget<'a>>,
+    cancel: Option<Arc<AtomicBool>>,
+    state: ExecState,
+    step: impl FnOnce(&mut StreamPrint<'a>) -> Result<T, MontyException>,
+) -> StepOutcome<T> {
+    let ExecState {
+        output,
+        budget,
+        mut usage,
+        handles,
+    } = state;
+    let mut print = StreamPrint::new(print_target, output);
+
+    let (result, report) = tracker::track_step(&budget, &mut usage, cancel, || step(&mut print));
+
+    let (output, output_budget) = print.finish();
+    StepOutcome {
+        result,
+        report,
+        output,
+        state: ExecState {
+            output: output_budget,
+            budget,
+            usage,
+            handles,
+        },
+    }
+}
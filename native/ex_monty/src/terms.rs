@@ -0,0 +1,53 @@
+use rustler::types::atom::Atom;
+use rustler::{Encoder, Env, Term};
+
+/// The atom `name`. Atoms are limited to 255 characters; a longer name is
+/// returned as a string rather than failing.
+pub fn atom<'a>(env: Env<'a>, name: &str) -> Term<'a> {
+    match Atom::from_str(env, name) {
+        Ok(atom) => atom.encode(env),
+        Err(_) => name.encode(env),
+    }
+}
+
+/// `{tag, elements...}`.
+pub fn tagged<'a>(env: Env<'a>, tag: &str, elements: &[Term<'a>]) -> Term<'a> {
+    let mut terms = Vec::with_capacity(elements.len() + 1);
+    terms.push(atom(env, tag));
+    terms.extend_from_slice(elements);
+    rustler::types::tuple::make_tuple(env, &terms)
+}
+
+/// A map from `entries`; a later entry replaces an earlier one with the same
+/// key. Putting into a map cannot fail, so a failed put is skipped instead of
+/// unwrapped.
+pub fn map<'a>(env: Env<'a>, entries: impl IntoIterator<Item = (Term<'a>, Term<'a>)>) -> Term<'a> {
+    entries
+        .into_iter()
+        .fold(rustler::types::map::map_new(env), |map, (key, value)| {
+            map.map_put(key, value).unwrap_or(map)
+        })
+}
+
+/// The struct `%module{fields...}`, where `module` is the Elixir module name
+/// without the `Elixir.` prefix.
+pub fn make_struct<'a>(env: Env<'a>, module: &str, fields: &[(&str, Term<'a>)]) -> Term<'a> {
+    let module = atom(env, &format!("Elixir.{module}"));
+    let fields = fields.iter().map(|(key, value)| (atom(env, key), *value));
+    map(
+        env,
+        std::iter::once((atom(env, "__struct__"), module)).chain(fields),
+    )
+}
+
+/// The value under the atom key `key` of a map, or `None` if `term` is not
+/// a map or has no such key.
+pub fn map_get<'a>(term: Term<'a>, key: &str) -> Option<Term<'a>> {
+    let key = Atom::from_str(term.get_env(), key).ok()?;
+    term.map_get(key.encode(term.get_env())).ok()
+}
+
+/// `nil`.
+pub fn nil(env: Env<'_>) -> Term<'_> {
+    rustler::types::atom::nil().encode(env)
+}
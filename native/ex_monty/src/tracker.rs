@@ -0,0 +1,405 @@
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use monty::{LimitedTracker, MontyException, ResourceError, ResourceLimits, ResourceTracker};
+use rustler::{Encoder, Env, Term};
+
+use crate::terms;
+
+/// Resource usage of one execution, accumulated across all of its steps.
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct Usage {
+    pub allocations: usize,
+    pub memory: usize,
+    pub peak_memory: usize,
+    pub max_recursion_depth: usize,
+    /// Time spent inside the interpreter (not in Elixir between steps).
+    pub elapsed: Duration,
+    /// CPU time the interpreter used, which is what `TimeBudget::Cpu` counts.
+    pub cpu_time: Duration,
+}
+
+/// Encode usage as `%{allocations: n, memory: n, peak_memory: n,
+/// max_recursion_depth: n, elapsed_secs: float, cpu_secs: float}`.
+pub fn encode_usage<'a>(env: Env<'a>, usage: &Usage) -> Term<'a> {
+    let entries = [
+        ("allocations", usage.allocations.encode(env)),
+        ("memory", usage.memory.encode(env)),
+        ("peak_memory", usage.peak_memory.encode(env)),
+        ("max_recursion_depth", usage.max_recursion_depth.encode(env)),
+        ("elapsed_secs", usage.elapsed.as_secs_f64().encode(env)),
+        ("cpu_secs", usage.cpu_time.as_secs_f64().encode(env)),
+    ];
+
+    terms::map(
+        env,
+        entries
+            .into_iter()
+            .map(|(key, value)| (terms::atom(env, key), value)),
+    )
+}
+
+/// How `max_duration_secs` is counted across the steps of an interactive
+/// execution.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum TimeBudget {
+    /// Only CPU time used by the interpreter, summed over all steps. Time
+    /// the thread spends descheduled does not count.
+    Cpu,
+    /// Wall-clock time since `start`, including time spent in Elixir between
+    /// steps. For a single `run` this is a plain wall-clock limit.
+    #[default]
+    Wall,
+}
+
+/// Limits that apply to an execution as a whole rather than to one step.
+///
+/// The time limit is enforced here instead of by `LimitedTracker`, so that it
+/// has the same meaning whether a session runs in one step or in many, and
+/// survives snapshot dump/load. The other limits are enforced by
+/// `LimitedTracker` and only copied here to report the remaining budget.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Budget {
+    pub max_duration: Option<Duration>,
+    pub time_budget: TimeBudget,
+    pub max_allocations: Option<usize>,
+    pub max_memory: Option<usize>,
+    started_at: SystemTime,
+}
+
+impl Default for Budget {
+    fn default() -> Self {
+        Self {
+            max_duration: None,
+            time_budget: TimeBudget::default(),
+            max_allocations: None,
+            max_memory: None,
+            started_at: SystemTime::now(),
+        }
+    }
+}
+
+impl Budget {
+    /// Start counting wall-clock time from now.
+    pub fn restart(&mut self) {
+        self.started_at = SystemTime::now();
+    }
+
+    /// Time counted against `max_duration` so far. `step_cpu` is the CPU
+    /// time used by the current step, which is not yet part of `usage`.
+    fn spent(&self, usage: &Usage, step_cpu: Duration) -> Duration {
+        match self.time_budget {
+            TimeBudget::Cpu => usage.cpu_time + step_cpu,
+            TimeBudget::Wall => self.started_at.elapsed().unwrap_or_default(),
+        }
+    }
+}
+
+/// Encode what is left of the budget as `%{time_secs: float, allocations: n,
+/// memory: n, output_bytes: n}`, with `nil` for limits that are not set.
+pub fn encode_remaining<'a>(
+    env: Env<'a>,
+    budget: &Budget,
+    usage: &Usage,
+    output_bytes: Option<usize>,
+) -> Term<'a> {
+    let nil = rustler::types::atom::nil().encode(env);
+    let time = budget.max_duration.map_or(nil, |limit| {
+        let spent = budget.spent(usage, Duration::ZERO);
+        limit.saturating_sub(spent).as_secs_f64().encode(env)
+    });
+    let allocations = budget.max_allocations.map_or(nil, |limit| {
+        limit.saturating_sub(usage.allocations).encode(env)
+    });
+    let memory = budget
+        .max_memory
+        .map_or(nil, |limit| limit.saturating_sub(usage.memory).encode(env));
+
+    let output_bytes = output_bytes.map_or(nil, |n| n.encode(env));
+
+    let entries = [
+        ("time_secs", time),
+        ("allocations", allocations),
+        ("memory", memory),
+        ("output_bytes", output_bytes),
+    ];
+
+    terms::map(
+        env,
+        entries
+            .into_iter()
+            .map(|(key, value)| (terms::atom(env, key), value)),
+    )
+}
+
+/// When a step started, by the wall clock and by the thread's CPU clock.
+#[derive(Clone, Copy)]
+struct StepClock {
+    wall: Instant,
+    cpu: Option<Duration>,
+}
+
+impl StepClock {
+    fn start() -> Self {
+        Self {
+            wall: Instant::now(),
+            cpu: thread_cpu_time(),
+        }
+    }
+
+    fn wall(&self) -> Duration {
+        self.wall.elapsed()
+    }
+
+    /// CPU time used by this thread since the step started. Falls back to
+    /// wall time where the platform has no per-thread CPU clock.
+    fn cpu(&self) -> Duration {
+        match (self.cpu, thread_cpu_time()) {
+            (Some(started), Some(now)) => now.saturating_sub(started),
+            _ => self.wall(),
+        }
+    }
+}
+
+/// CPU time used by the calling thread so far.
+#[cfg(unix)]
+fn thread_cpu_time() -> Option<Duration> {
+    let mut now = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // SAFETY: `now` is a valid timespec for the call to write into.
+    if unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut now) } != 0 {
+        return None;
+    }
+    let secs = u64::try_from(now.tv_sec).ok()?;
+    let nanos = u32::try_from(now.tv_nsec).ok()?;
+    Some(Duration::new(secs, nanos))
+}
+
+#[cfg(not(unix))]
+fn thread_cpu_time() -> Option<Duration> {
+    None
+}
+
+/// Bookkeeping for the step currently running on this thread.
+#[derive(Default)]
+struct StepContext {
+    budget: Budget,
+    started: Option<StepClock>,
+    /// Step wall time before which the CPU budget cannot run out, since a
+    /// thread uses at most as much CPU time as wall time. Reading the CPU
+    /// clock is a syscall, so `check_time` skips it until then.
+    cpu_check_after: Duration,
+    cancel: Option<Arc<AtomicBool>>,
+    usage: Usage,
+    report: StepReport,
+}
+
+impl StepContext {
+    /// Time counted against `limit` so far, or `None` while it certainly
+    /// has not run out.
+    fn spent(&mut self, limit: Duration) -> Option<Duration> {
+        let clock = self.started?;
+        if self.budget.time_budget == TimeBudget::Wall {
+            return Some(self.budget.spent(&self.usage, Duration::ZERO));
+        }
+
+        let wall = clock.wall();
+        if wall < self.cpu_check_after {
+            return None;
+        }
+        let spent = self.budget.spent(&self.usage, clock.cpu());
+        // CPU time grows no faster than wall time, so the budget cannot run
+        // out before this much more wall time has passed.
+        self.cpu_check_after = wall + limit.saturating_sub(spent);
+        Some(spent)
+    }
+}
+
+/// How a step ended, as seen by the tracker.
+#[derive(Debug, Default)]
+pub struct StepReport {
+    /// The resource limit that was exceeded, if any.
+    pub tripped: Option<ResourceError>,
+    /// Whether the step was stopped by its cancellation token.
+    pub cancelled: bool,
+}
+
+thread_local! {
+    static STEP: RefCell<StepContext> = RefCell::new(StepContext::default());
+}
+
+/// Resource tracker used for every execution.
+///
+/// Enforcement is delegated to `LimitedTracker`, except for the session time
+/// budget (see `Budget`) and cancellation; otherwise the wrapper only observes
+/// the calls it receives so the NIF can report usage and which limit stopped
+/// the script. The interpreter turns limit errors into ordinary Python exceptions,
+/// which would otherwise be all that reaches Elixir.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ExTracker {
+    inner: LimitedTracker,
+}
+
+impl ExTracker {
+    pub fn new(limits: ResourceLimits) -> Self {
+        Self {
+            inner: LimitedTracker::new(limits),
+        }
+    }
+}
+
+/// Run one interpreter step under `budget`, adding its resource usage to
+/// `usage` and reporting whether a limit or `cancel` stopped it.
+///
+/// The tracker lives inside the snapshot while a step runs, so it reads the
+/// budget from and reports through a thread-local instead of shared state; a
+/// step always runs to completion on the calling thread.
+pub fn track_step<R>(
+    budget: &Budget,
+    usage: &mut Usage,
+    cancel: Option<Arc<AtomicBool>>,
+    step: impl FnOnce() -> R,
+) -> (R, StepReport) {
+    let started = StepClock::start();
+    STEP.with(|s| {
+        *s.borrow_mut() = StepContext {
+            budget: budget.clone(),
+            started: Some(started),
+            cpu_check_after: budget
+                .max_duration
+                .unwrap_or_default()
+                .saturating_sub(usage.cpu_time),
+            cancel,
+            usage: std::mem::take(usage),
+            report: StepReport::default(),
+        }
+    });
+
+    let result = step();
+    let (elapsed, cpu_time) = (started.wall(), started.cpu());
+
+    let context = STEP.with(|s| std::mem::take(&mut *s.borrow_mut()));
+    *usage = context.usage;
+    usage.elapsed += elapsed;
+    usage.cpu_time += cpu_time;
+    (result, context.report)
+}
+
+/// Fail every check once the step's cancellation token is set, so a script
+/// cannot swallow the cancellation with `try`/`except`.
+fn check_cancelled() -> Result<(), ResourceError> {
+    let cancelled = STEP.with(|s| {
+        let mut context = s.borrow_mut();
+        if context
+            .cancel
+            .as_ref()
+            .is_some_and(|flag| flag.load(Ordering::Relaxed))
+        {
+            context.report.cancelled = true;
+        }
+        context.report.cancelled
+    });
+
+    if cancelled {
+        return Err(ResourceError::Exception(MontyException::new(
+            monty::ExcType::RuntimeError,
+            Some("execution cancelled".to_owned()),
+        )));
+    }
+    Ok(())
+}
+
+fn with_usage(f: impl FnOnce(&mut Usage)) {
+    STEP.with(|s| f(&mut s.borrow_mut().usage));
+}
+
+fn record<T>(result: Result<T, ResourceError>) -> Result<T, ResourceError> {
+    if let Err(err) = &result {
+        let limit = match err {
+            ResourceError::Allocation { limit, count } => ResourceError::Allocation {
+                limit: *limit,
+                count: *count,
+            },
+            ResourceError::Time { limit, elapsed } => ResourceError::Time {
+                limit: *limit,
+                elapsed: *elapsed,
+            },
+            ResourceError::Memory { limit, used } => ResourceError::Memory {
+                limit: *limit,
+                used: *used,
+            },
+            ResourceError::Recursion { limit, depth } => ResourceError::Recursion {
+                limit: *limit,
+                depth: *depth,
+            },
+            // Not a limit: already an ordinary exception.
+            ResourceError::Exception(_) => return result,
+        };
+        STEP.with(|s| s.borrow_mut().report.tripped = Some(limit));
+    }
+    result
+}
+
+impl ResourceTracker for ExTracker {
+    fn on_allocate(&mut self, get_size: impl FnOnce() -> usize) -> Result<(), ResourceError> {
+        check_cancelled()?;
+        let size = get_size();
+        record(self.inner.on_allocate(|| size))?;
+        with_usage(|usage| {
+            usage.allocations += 1;
+            usage.memory += size;
+            usage.peak_memory = usage.peak_memory.max(usage.memory);
+        });
+        Ok(())
+    }
+
+    fn on_free(&mut self, get_size: impl FnOnce() -> usize) {
+        let size = get_size();
+        self.inner.on_free(|| size);
+        with_usage(|usage| usage.memory = usage.memory.saturating_sub(size));
+    }
+
+    fn check_time(&self) -> Result<(), ResourceError> {
+        check_cancelled()?;
+        record(self.inner.check_time())?;
+
+        let over_budget = STEP.with(|s| {
+            let mut context = s.borrow_mut();
+            let limit = context.budget.max_duration?;
+            let spent = context.spent(limit)?;
+            (spent > limit).then_some(ResourceError::Time {
+                limit,
+                elapsed: spent,
+            })
+        });
+
+        match over_budget {
+            Some(err) => record(Err(err)),
+            None => Ok(()),
+        }
+    }
+
+    fn check_recursion_depth(&self, current_depth: usize) -> Result<(), ResourceError> {
+        record(self.inner.check_recursion_depth(current_depth))?;
+        with_usage(|usage| {
+            usage.max_recursion_depth = usage.max_recursion_depth.max(current_depth);
+        });
+        Ok(())
+    }
+
+    fn check_large_result(&self, estimated_bytes: usize) -> Result<(), ResourceError> {
+        record(self.inner.check_large_result(estimated_bytes))
+    }
+
+    fn should_gc(&self) -> bool {
+        self.inner.should_gc()
+    }
+
+    fn on_gc_complete(&mut self) {
+        self.inner.on_gc_complete()
+    }
+}
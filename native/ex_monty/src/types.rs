@@ -1,86 +1,166 @@
-use monty::{MontyObject, OsFunction, ResourceLimits};
+use monty::{ExcType, MontyException, MontyObject, OsFunction, ResourceLimits};
 use num_bigint::BigInt;
 use rustler::types::atom::Atom;
+use rustler::types::binary::NewBinary;
 use rustler::types::map::MapIterator;
 use rustler::types::tuple::get_tuple;
-use rustler::{Encoder, Env, NifResult, Term};
+use rustler::{Binary, Encoder, Env, NifResult, Term};
 use std::collections::{BTreeSet, HashMap, HashSet};
+use std::str::FromStr;
 use std::time::Duration;
 
+use crate::error::{Detail, Error};
+use crate::handles::HandleTable;
+use crate::print::{OutputBudget, OutputPolicy};
+use crate::std_structs;
+use crate::terms;
+use crate::tracker::{Budget, TimeBudget};
+
 // ── Encoding: MontyObject → Erlang Term ──────────────────────────────────────
 
-pub fn encode_monty_object<'a>(env: Env<'a>, obj: &MontyObject) -> Term<'a> {
+/// How Python dicts are returned to Elixir.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DictFormat {
+    /// An Erlang map; insertion order is lost. Falls back to `Tagged` when
+    /// two keys encode to the same term.
+    #[default]
+    Map,
+    /// A list of `{key, value}` tuples in insertion order.
+    Pairs,
+    /// `{:dict, pairs}`, which decodes back into a dict.
+    Tagged,
+}
+
+/// Per-call options for encoding Python values.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct EncodeOpts {
+    pub dict_format: DictFormat,
+}
+
+impl EncodeOpts {
+    /// Decode the encoding keys of a NIF options map.
+    pub fn decode(opts: Term) -> NifResult<Self> {
+        let dict_format = match get_option(opts, "dict_format")? {
+            None => DictFormat::Map,
+            Some(val) => match atom_choice("dict_format", val, &["map", "pairs", "tagged"])? {
+                "map" => DictFormat::Map,
+                "pairs" => DictFormat::Pairs,
+                _ => DictFormat::Tagged,
+            },
+        };
+        Ok(Self { dict_format })
+    }
+}
+
+pub fn encode_monty_object<'a>(
+    env: Env<'a>,
+    obj: &MontyObject,
+    opts: &EncodeOpts,
+    handles: &HandleTable,
+) -> Term<'a> {
+    if let Some(term) = handles
+        .lookup(env, obj)
+        .or_else(|| encode_atom_object(env, obj))
+    {
+        return term;
+    }
+
     match obj {
         MontyObject::None => rustler::types::atom::nil().encode(env),
         MontyObject::Bool(b) => b.encode(env),
         MontyObject::Int(i) => i.encode(env),
         MontyObject::BigInt(bi) => bi.encode(env),
-        MontyObject::Float(f) => f.encode(env),
+        MontyObject::Float(f) => encode_float(env, *f),
         MontyObject::String(s) => s.encode(env),
         MontyObject::Bytes(b) => {
-            let tag = Atom::from_str(env, "bytes").unwrap();
-            let mut owned = rustler::OwnedBinary::new(b.len()).unwrap();
-            owned.as_mut_slice().copy_from_slice(b);
-            let binary = owned.release(env);
-            rustler::types::tuple::make_tuple(env, &[tag.encode(env), binary.encode(env)])
+            let mut binary = NewBinary::new(env, b.len());
+            binary.as_mut_slice().copy_from_slice(b);
+            let binary: Binary = binary.into();
+            terms::tagged(env, "bytes", &[binary.encode(env)])
         }
-        MontyObject::Ellipsis => Atom::from_str(env, "ellipsis").unwrap().encode(env),
+        MontyObject::Ellipsis => terms::atom(env, "ellipsis"),
         MontyObject::List(items) => {
-            let terms: Vec<Term> = items.iter().map(|i| encode_monty_object(env, i)).collect();
+            let terms: Vec<Term> = items
+                .iter()
+                .map(|i| encode_monty_object(env, i, opts, handles))
+                .collect();
             terms.encode(env)
         }
         MontyObject::Tuple(items) => {
-            let terms: Vec<Term> = items.iter().map(|i| encode_monty_object(env, i)).collect();
+            let terms: Vec<Term> = items
+                .iter()
+                .map(|i| encode_monty_object(env, i, opts, handles))
+                .collect();
             rustler::types::tuple::make_tuple(env, &terms)
         }
         MontyObject::Dict(pairs) => {
-            let mut map = rustler::types::map::map_new(env);
+            let mut items: Vec<(Term, Term)> = Vec::new();
             for (k, v) in pairs {
-                let key = encode_monty_object(env, k);
-                let val = encode_monty_object(env, v);
-                map = map.map_put(key, val).unwrap();
+                let key = encode_monty_object(env, k, opts, handles);
+                let val = encode_monty_object(env, v, opts, handles);
+                items.push((key, val));
+            }
+            if opts.dict_format == DictFormat::Map {
+                if let Some(map) = encode_dict_map(env, &items) {
+                    return map;
+                }
+            }
+            let items: Vec<Term> = items
+                .into_iter()
+                .map(|(key, val)| rustler::types::tuple::make_tuple(env, &[key, val]))
+                .collect();
+            if opts.dict_format == DictFormat::Pairs {
+                items.encode(env)
+            } else {
+                terms::tagged(env, "dict", &[items.encode(env)])
             }
-            map
         }
-        MontyObject::Set(items) | MontyObject::FrozenSet(items) => {
-            let members: Vec<Term> = items.iter().map(|i| encode_monty_object(env, i)).collect();
+        MontyObject::Set(items) => {
+            let members: Vec<Term> = items
+                .iter()
+                .map(|i| encode_monty_object(env, i, opts, handles))
+                .collect();
             encode_mapset(env, &members)
         }
-        MontyObject::Path(p) => {
-            let tag = Atom::from_str(env, "path").unwrap();
-            rustler::types::tuple::make_tuple(env, &[tag.encode(env), p.encode(env)])
+        MontyObject::FrozenSet(items) => {
+            let members: Vec<Term> = items
+                .iter()
+                .map(|i| encode_monty_object(env, i, opts, handles))
+                .collect();
+            terms::tagged(env, "frozenset", &[encode_mapset(env, &members)])
         }
+        MontyObject::Path(p) => terms::tagged(env, "path", &[p.encode(env)]),
         MontyObject::NamedTuple {
             type_name,
             field_names,
             values,
         } => {
-            let tag = Atom::from_str(env, "named_tuple").unwrap();
-
+            if let Some(term) = std_structs::encode(env, type_name, field_names, values) {
+                return term;
+            }
             let fields: Vec<Term> = field_names
                 .iter()
                 .zip(values.iter())
                 .map(|(fname, val)| {
                     let key = fname.encode(env);
-                    let value = encode_monty_object(env, val);
+                    let value = encode_monty_object(env, val, opts, handles);
                     rustler::types::tuple::make_tuple(env, &[key, value])
                 })
                 .collect();
 
-            rustler::types::tuple::make_tuple(
+            terms::tagged(
                 env,
-                &[tag.encode(env), type_name.encode(env), fields.encode(env)],
+                "named_tuple",
+                &[type_name.encode(env), fields.encode(env)],
             )
         }
         MontyObject::Dataclass {
             name,
+            type_id,
             field_names,
             attrs,
             frozen,
-            ..
         } => {
-            let struct_atom = Atom::from_str(env, "Elixir.ExMonty.Dataclass").unwrap();
-            let mut fields_map = rustler::types::map::map_new(env);
             let attr_map: std::collections::HashMap<String, &MontyObject> = attrs
                 .into_iter()
                 .filter_map(|(k, v)| {
@@ -91,98 +171,91 @@ pub fn encode_monty_object<'a>(env: Env<'a>, obj: &MontyObject) -> Term<'a> {
                     }
                 })
                 .collect();
-            for fname in field_names {
-                if let Some(val) = attr_map.get(fname) {
-                    let key = fname.encode(env);
-                    let value = encode_monty_object(env, val);
-                    fields_map = fields_map.map_put(key, value).unwrap();
-                }
-            }
-            rustler::types::map::map_new(env)
-                .map_put(
-                    Atom::from_str(env, "__struct__").unwrap().encode(env),
-                    struct_atom.encode(env),
-                )
-                .unwrap()
-                .map_put(
-                    Atom::from_str(env, "name").unwrap().encode(env),
-                    name.encode(env),
-                )
-                .unwrap()
-                .map_put(
-                    Atom::from_str(env, "fields").unwrap().encode(env),
-                    fields_map,
-                )
-                .unwrap()
-                .map_put(
-                    Atom::from_str(env, "frozen").unwrap().encode(env),
-                    frozen.encode(env),
-                )
-                .unwrap()
+            let fields = terms::map(
+                env,
+                field_names.iter().filter_map(|fname| {
+                    let val = attr_map.get(fname)?;
+                    Some((
+                        fname.encode(env),
+                        encode_monty_object(env, val, opts, handles),
+                    ))
+                }),
+            );
+            terms::make_struct(
+                env,
+                "ExMonty.Dataclass",
+                &[
+                    ("name", name.encode(env)),
+                    ("type_id", type_id.encode(env)),
+                    ("fields", fields),
+                    ("field_names", field_names.encode(env)),
+                    ("frozen", frozen.encode(env)),
+                ],
+            )
         }
         MontyObject::Exception { exc_type, arg } => {
-            let struct_atom = Atom::from_str(env, "Elixir.ExMonty.Exception").unwrap();
-            let type_str = exc_type.to_string();
-            let type_atom = Atom::from_str(env, &snake_case(&type_str)).unwrap();
             let message = match arg {
                 Some(msg) => msg.encode(env),
-                None => rustler::types::atom::nil().encode(env),
+                None => terms::nil(env),
             };
-            rustler::types::map::map_new(env)
-                .map_put(
-                    Atom::from_str(env, "__struct__").unwrap().encode(env),
-                    struct_atom.encode(env),
-                )
-                .unwrap()
-                .map_put(
-                    Atom::from_str(env, "type").unwrap().encode(env),
-                    type_atom.encode(env),
-                )
-                .unwrap()
-                .map_put(Atom::from_str(env, "message").unwrap().encode(env), message)
-                .unwrap()
-                .map_put(
-                    Atom::from_str(env, "traceback").unwrap().encode(env),
-                    Vec::<Term>::new().encode(env),
-                )
-                .unwrap()
-        }
-        MontyObject::Type(ty) => {
-            let repr = ty.to_string();
-            Atom::from_str(env, &snake_case(&repr))
-                .unwrap_or_else(|_| Atom::from_str(env, "unknown_type").unwrap())
-                .encode(env)
-        }
-        MontyObject::BuiltinFunction(_) => {
-            Atom::from_str(env, "builtin_function").unwrap().encode(env)
-        }
-        MontyObject::Repr(s) => {
-            let tag = Atom::from_str(env, "repr").unwrap();
-            rustler::types::tuple::make_tuple(env, &[tag.encode(env), s.encode(env)])
-        }
-        MontyObject::Cycle(_, desc) => {
-            let tag = Atom::from_str(env, "cycle").unwrap();
-            rustler::types::tuple::make_tuple(env, &[tag.encode(env), desc.encode(env)])
+            let empty: Vec<Term> = Vec::new();
+            terms::make_struct(
+                env,
+                "ExMonty.Exception",
+                &[
+                    ("kind", terms::atom(env, "python_exception")),
+                    ("type", terms::atom(env, &snake_case(&exc_type.to_string()))),
+                    ("message", message),
+                    (
+                        "args",
+                        arg.iter().cloned().collect::<Vec<String>>().encode(env),
+                    ),
+                    ("traceback", empty.encode(env)),
+                    ("limit", terms::nil(env)),
+                    ("diagnostics", empty.encode(env)),
+                ],
+            )
         }
+        MontyObject::Type(ty) => terms::atom(env, &snake_case(&ty.to_string())),
+        MontyObject::BuiltinFunction(_) => terms::atom(env, "builtin_function"),
+        MontyObject::Repr(s) => terms::tagged(env, "repr", &[s.encode(env)]),
+        MontyObject::Cycle(_, desc) => terms::tagged(env, "cycle", &[desc.encode(env)]),
     }
 }
 
+/// Erlang floats cannot be NaN or infinite, so those are encoded as the
+/// atoms `:nan`, `:infinity` and `:neg_infinity`. They only decode back to
+/// floats with `float_atoms: :float`.
+fn encode_float(env: Env<'_>, f: f64) -> Term<'_> {
+    let special = if f.is_nan() {
+        "nan"
+    } else if f == f64::INFINITY {
+        "infinity"
+    } else if f == f64::NEG_INFINITY {
+        "neg_infinity"
+    } else {
+        return f.encode(env);
+    };
+    terms::atom(env, special)
+}
+
+/// Build a map from encoded dict items, or `None` if two distinct Python keys
+/// encode to the same term (e.g. two NaN keys, or two objects with the same
+/// repr). A map would silently drop one of them, so the caller falls back to
+/// the tagged pair list.
+fn encode_dict_map<'a>(env: Env<'a>, items: &[(Term<'a>, Term<'a>)]) -> Option<Term<'a>> {
+    let mut map = rustler::types::map::map_new(env);
+    for (key, val) in items {
+        map = map.map_put(*key, *val).ok()?;
+    }
+    (map.map_size().ok()? == items.len()).then_some(map)
+}
+
 fn encode_mapset<'a>(env: Env<'a>, members: &[Term<'a>]) -> Term<'a> {
-    let struct_atom = Atom::from_str(env, "Elixir.MapSet").unwrap();
-    let mut inner_map = rustler::types::map::map_new(env);
     let placeholder: Vec<Term> = vec![];
-    let placeholder_term = placeholder.encode(env);
-    for member in members {
-        inner_map = inner_map.map_put(*member, placeholder_term).unwrap();
-    }
-    rustler::types::map::map_new(env)
-        .map_put(
-            Atom::from_str(env, "__struct__").unwrap().encode(env),
-            struct_atom.encode(env),
-        )
-        .unwrap()
-        .map_put(Atom::from_str(env, "map").unwrap().encode(env), inner_map)
-        .unwrap()
+    let placeholder = placeholder.encode(env);
+    let inner_map = terms::map(env, members.iter().map(|member| (*member, placeholder)));
+    terms::make_struct(env, "MapSet", &[("map", inner_map)])
 }
 
 const STAT_RESULT_FIELD_ORDER: [&str; 10] = [
@@ -192,138 +265,617 @@ const STAT_RESULT_FIELD_ORDER: [&str; 10] = [
 
 // ── Decoding: Erlang Term → MontyObject ──────────────────────────────────────
 
-pub fn decode_monty_object<'a>(env: Env<'a>, term: Term<'a>) -> NifResult<MontyObject> {
-    // nil, true, false, ellipsis atoms
-    if term.is_atom() {
-        let atom_str: String = term.atom_to_string().map_err(|_| rustler::Error::BadArg)?;
-        return match atom_str.as_str() {
-            "nil" => Ok(MontyObject::None),
-            "true" => Ok(MontyObject::Bool(true)),
-            "false" => Ok(MontyObject::Bool(false)),
-            "ellipsis" => Ok(MontyObject::Ellipsis),
-            other => Ok(MontyObject::String(other.to_owned())),
+/// How atoms are decoded, apart from `nil`, `true`, `false` and the atoms
+/// that stand for special floats and `...`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AtomPolicy {
+    /// A Python `str` with the atom's name.
+    #[default]
+    String,
+    /// An error naming where the atom was found.
+    Strict,
+    /// An `Atom(name=...)` object, which encodes back to the same atom.
+    Tagged,
+}
+
+/// Per-call options for decoding Elixir terms.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DecodeOpts {
+    pub atoms: AtomPolicy,
+    /// Decode keyword lists (`[a: 1]`) as dicts with string keys.
+    pub keyword_dicts: bool,
+    /// Decode printable charlists (`'abc'`) as strings.
+    pub charlist_strings: bool,
+    /// Decode `:nan`, `:infinity` and `:neg_infinity` as floats instead of
+    /// following the atom policy.
+    pub float_atoms: bool,
+}
+
+impl DecodeOpts {
+    /// Decode the decoding keys of a NIF options map.
+    pub fn decode(opts: Term) -> NifResult<Self> {
+        let atoms = match get_option(opts, "atom_policy")? {
+            None => AtomPolicy::String,
+            Some(val) => match atom_choice("atom_policy", val, &["string", "strict", "tagged"])? {
+                "string" => AtomPolicy::String,
+                "strict" => AtomPolicy::Strict,
+                _ => AtomPolicy::Tagged,
+            },
+        };
+        let keyword_dicts = match get_option(opts, "keyword_lists")? {
+            None => false,
+            Some(val) => atom_choice("keyword_lists", val, &["list", "dict"])? == "dict",
         };
+        let charlist_strings = match get_option(opts, "charlists")? {
+            None => false,
+            Some(val) => atom_choice("charlists", val, &["list", "string"])? == "string",
+        };
+        let float_atoms = match get_option(opts, "float_atoms")? {
+            None => false,
+            Some(val) => atom_choice("float_atoms", val, &["atom", "float"])? == "float",
+        };
+        Ok(Self {
+            atoms,
+            keyword_dicts,
+            charlist_strings,
+            float_atoms,
+        })
     }
+}
 
-    // Try i64 first (most common integer case)
-    if let Ok(i) = term.decode::<i64>() {
-        return Ok(MontyObject::Int(i));
+/// Dataclass name and type id of tagged atoms.
+const ATOM_NAME: &str = "Atom";
+const ATOM_TYPE_ID: u64 = u64::MAX - 1;
+
+/// Dataclass type ids from here up are reserved for objects made by the NIF
+/// (`ATOM_TYPE_ID`, and `u64::MAX`, which handles used to have). A
+/// `%ExMonty.Dataclass{}` input cannot claim them.
+const RESERVED_TYPE_IDS: u64 = ATOM_TYPE_ID;
+
+fn atom_object(name: String) -> MontyObject {
+    MontyObject::Dataclass {
+        name: ATOM_NAME.to_owned(),
+        type_id: ATOM_TYPE_ID,
+        field_names: vec!["name".to_owned()],
+        attrs: vec![(
+            MontyObject::String("name".to_owned()),
+            MontyObject::String(name),
+        )]
+        .into(),
+        frozen: true,
     }
+}
 
-    // Big integer (arbitrary precision)
-    if let Ok(bi) = term.decode::<BigInt>() {
-        return Ok(MontyObject::BigInt(bi));
+/// The atom behind an `Atom(name=...)` object made by `AtomPolicy::Tagged`.
+fn encode_atom_object<'a>(env: Env<'a>, obj: &MontyObject) -> Option<Term<'a>> {
+    let MontyObject::Dataclass {
+        type_id: ATOM_TYPE_ID,
+        attrs,
+        ..
+    } = obj
+    else {
+        return None;
+    };
+    let name = attrs.into_iter().find_map(|(k, v)| match (k, v) {
+        (MontyObject::String(k), MontyObject::String(name)) if k == "name" => Some(name),
+        _ => None,
+    })?;
+    Atom::from_str(env, name).ok().map(|atom| atom.encode(env))
+}
+
+/// Decodes the Elixir terms passed into one execution.
+///
+/// Opaque terms are added to the execution's handle table, and the path to
+/// the term being decoded is tracked for error messages (`x[0]["key"]`).
+pub struct Decoder<'h> {
+    opts: DecodeOpts,
+    handles: &'h mut HandleTable,
+    path: Vec<String>,
+}
+
+impl<'h> Decoder<'h> {
+    pub fn new(opts: DecodeOpts, handles: &'h mut HandleTable) -> Self {
+        Self {
+            opts,
+            handles,
+            path: Vec::new(),
+        }
     }
 
-    // Float
-    if term.is_float() {
-        let f: f64 = term.decode()?;
-        return Ok(MontyObject::Float(f));
+    /// Decode a top-level value; `name` starts its path in error messages.
+    /// A malformed struct or tuple that fails without a more specific error
+    /// is reported as an `:input_error` at `name`.
+    pub fn decode_named<'a>(
+        &mut self,
+        env: Env<'a>,
+        name: &str,
+        term: Term<'a>,
+    ) -> NifResult<MontyObject> {
+        self.at(name.to_owned(), |d| d.decode(env, term))
+            .map_err(|err| match err {
+                rustler::Error::BadArg => {
+                    Error::input_error("invalid value", name.to_owned()).into()
+                }
+                err => err,
+            })
     }
 
-    // Binary/String
-    if term.is_binary() {
-        if let Ok(s) = term.decode::<String>() {
-            return Ok(MontyObject::String(s));
-        }
+    /// Run `f` with `segment` appended to the current path.
+    fn at<T>(
+        &mut self,
+        segment: String,
+        f: impl FnOnce(&mut Self) -> NifResult<T>,
+    ) -> NifResult<T> {
+        self.path.push(segment);
+        let result = f(self);
+        self.path.pop();
+        result
+    }
 
-        let binary: rustler::Binary = term.decode()?;
-        return Ok(MontyObject::Bytes(binary.as_slice().to_vec()));
+    /// An `:input_error` for the term at the current path.
+    fn error(&self, message: &str) -> rustler::Error {
+        Error::input_error(message, self.path.concat()).into()
     }
 
-    // Tuple - check for tagged tuples first
-    if let Ok(elements) = get_tuple(term) {
-        // Tagged NamedTuple: {:named_tuple, type_name, fields}
-        if elements.len() == 3 {
-            if let Ok(tag) = elements[0].atom_to_string() {
-                if tag == "named_tuple" {
-                    return decode_named_tuple(env, elements[1], elements[2]);
-                }
+    pub fn decode<'a>(&mut self, env: Env<'a>, term: Term<'a>) -> NifResult<MontyObject> {
+        // nil, true, false, ellipsis atoms, and the special floats if asked
+        if term.is_atom() {
+            let atom_str: String = term.atom_to_string().map_err(|_| rustler::Error::BadArg)?;
+            let float_atoms = self.opts.float_atoms;
+            return match atom_str.as_str() {
+                "nil" => Ok(MontyObject::None),
+                "true" => Ok(MontyObject::Bool(true)),
+                "false" => Ok(MontyObject::Bool(false)),
+                "ellipsis" => Ok(MontyObject::Ellipsis),
+                "nan" if float_atoms => Ok(MontyObject::Float(f64::NAN)),
+                "infinity" if float_atoms => Ok(MontyObject::Float(f64::INFINITY)),
+                "neg_infinity" if float_atoms => Ok(MontyObject::Float(f64::NEG_INFINITY)),
+                _ => match self.opts.atoms {
+                    AtomPolicy::String => Ok(MontyObject::String(atom_str)),
+                    AtomPolicy::Strict => Err(self.error(&format!("unsupported atom :{atom_str}"))),
+                    AtomPolicy::Tagged => Ok(atom_object(atom_str)),
+                },
+            };
+        }
+
+        // Try i64 first (most common integer case)
+        if let Ok(i) = term.decode::<i64>() {
+            return Ok(MontyObject::Int(i));
+        }
+
+        // Big integer (arbitrary precision)
+        if let Ok(bi) = term.decode::<BigInt>() {
+            return Ok(MontyObject::BigInt(bi));
+        }
+
+        // Float
+        if term.is_float() {
+            let f: f64 = term.decode()?;
+            return Ok(MontyObject::Float(f));
+        }
+
+        // Binary/String
+        if term.is_binary() {
+            if let Ok(s) = term.decode::<String>() {
+                return Ok(MontyObject::String(s));
             }
+
+            let binary: rustler::Binary = term.decode()?;
+            return Ok(MontyObject::Bytes(binary.as_slice().to_vec()));
         }
 
-        if elements.len() == 2 {
-            if let Ok(tag) = elements[0].atom_to_string() {
-                match tag.as_str() {
-                    "bytes" => {
-                        let binary: rustler::Binary = elements[1].decode()?;
-                        return Ok(MontyObject::Bytes(binary.as_slice().to_vec()));
+        // Tuple - check for tagged tuples first
+        if let Ok(elements) = get_tuple(term) {
+            // Tagged NamedTuple: {:named_tuple, type_name, fields}
+            if elements.len() == 3 {
+                if let Ok(tag) = elements[0].atom_to_string() {
+                    if tag == "named_tuple" {
+                        return self.decode_named_tuple(env, elements[1], elements[2]);
                     }
-                    "path" => {
-                        let path: String = elements[1].decode()?;
-                        return Ok(MontyObject::Path(path));
+                }
+            }
+
+            if elements.len() == 2 {
+                if let Ok(tag) = elements[0].atom_to_string() {
+                    match tag.as_str() {
+                        "bytes" => {
+                            let binary: rustler::Binary = elements[1].decode()?;
+                            return Ok(MontyObject::Bytes(binary.as_slice().to_vec()));
+                        }
+                        "path" => {
+                            let path: String = elements[1].decode()?;
+                            return Ok(MontyObject::Path(path));
+                        }
+                        "repr" => {
+                            let repr: String = elements[1].decode()?;
+                            if HandleTable::parse_repr(&repr).is_some() {
+                                return Err(self.error("handle reprs cannot be passed in"));
+                            }
+                            return Ok(MontyObject::Repr(repr));
+                        }
+                        "dict" => return self.decode_dict_pairs(env, elements[1]),
+                        "frozenset" => {
+                            let items = self.decode_mapset(env, elements[1])?;
+                            return Ok(MontyObject::FrozenSet(items));
+                        }
+                        _ => {}
                     }
-                    "repr" => {
-                        let repr: String = elements[1].decode()?;
-                        return Ok(MontyObject::Repr(repr));
+                }
+            }
+            // Check for bigint tagged tuple {:__bigint__, sign, bytes}
+            if elements.len() == 3 {
+                if let Ok(tag) = elements[0].atom_to_string() {
+                    if tag == "__bigint__" {
+                        let sign: i32 = elements[1].decode()?;
+                        let binary: rustler::Binary = elements[2].decode()?;
+                        let num_sign = match sign {
+                            -1 => num_bigint::Sign::Minus,
+                            0 => num_bigint::Sign::NoSign,
+                            _ => num_bigint::Sign::Plus,
+                        };
+                        let bi = BigInt::from_bytes_be(num_sign, binary.as_slice());
+                        return Ok(MontyObject::BigInt(bi));
                     }
-                    _ => {}
                 }
             }
+            // Regular tuple
+            let items: Vec<MontyObject> = elements
+                .iter()
+                .enumerate()
+                .map(|(i, t)| self.at(format!("[{i}]"), |d| d.decode(env, *t)))
+                .collect::<NifResult<Vec<_>>>()?;
+            return Ok(MontyObject::Tuple(items));
         }
-        // Check for bigint tagged tuple {:__bigint__, sign, bytes}
-        if elements.len() == 3 {
-            if let Ok(tag) = elements[0].atom_to_string() {
-                if tag == "__bigint__" {
-                    let sign: i32 = elements[1].decode()?;
-                    let binary: rustler::Binary = elements[2].decode()?;
-                    let num_sign = match sign {
-                        -1 => num_bigint::Sign::Minus,
-                        0 => num_bigint::Sign::NoSign,
-                        _ => num_bigint::Sign::Plus,
-                    };
-                    let bi = BigInt::from_bytes_be(num_sign, binary.as_slice());
-                    return Ok(MontyObject::BigInt(bi));
+
+        // List
+        if term.is_list() {
+            let list: Vec<Term> = term.decode().map_err(|_| self.error("improper list"))?;
+            if self.opts.keyword_dicts && is_keyword_list(&list) {
+                return self.decode_keyword_list(env, &list);
+            }
+            if self.opts.charlist_strings {
+                if let Some(s) = charlist_string(&list) {
+                    return Ok(MontyObject::String(s));
                 }
             }
+            let items: Vec<MontyObject> = list
+                .into_iter()
+                .enumerate()
+                .map(|(i, t)| self.at(format!("[{i}]"), |d| d.decode(env, t)))
+                .collect::<NifResult<Vec<_>>>()?;
+            return Ok(MontyObject::List(items));
         }
-        // Regular tuple
-        let items: Vec<MontyObject> = elements
-            .iter()
-            .map(|t| decode_monty_object(env, *t))
-            .collect::<NifResult<Vec<_>>>()?;
-        return Ok(MontyObject::Tuple(items));
+
+        // Map - check for MapSet struct
+        if term.is_map() {
+            if let Some(struct_val) = terms::map_get(term, "__struct__") {
+                if let Ok(struct_name) = struct_val.atom_to_string() {
+                    if struct_name == "Elixir.MapSet" {
+                        return Ok(MontyObject::Set(self.decode_mapset(env, term)?));
+                    }
+                    if struct_name == "Elixir.ExMonty.Dataclass" {
+                        return self.decode_dataclass(env, term);
+                    }
+                    if struct_name == "Elixir.ExMonty.Exception" {
+                        let (exc_type, arg) = self.decode_exception_parts(term)?;
+                        return Ok(MontyObject::Exception { exc_type, arg });
+                    }
+                    if let Some(result) = std_structs::decode(&struct_name, term) {
+                        return result.map_err(|msg| self.error(&msg));
+                    }
+                }
+            }
+            // Regular map → Dict
+            let iter = MapIterator::new(term).ok_or(rustler::Error::BadArg)?;
+            let pairs: Vec<(MontyObject, MontyObject)> = iter
+                .map(|(k, v)| self.at(key_segment(k), |d| d.decode_pair(env, k, v)))
+                .collect::<NifResult<Vec<_>>>()?;
+            return Ok(MontyObject::dict(pairs));
+        }
+
+        // Pids, references, funs and ports
+        if HandleTable::is_opaque(term) {
+            return Ok(self.handles.insert(term));
+        }
+
+        Err(self.error("unsupported value"))
     }
 
-    // List
-    if term.is_list() {
-        let list: Vec<Term> = term.decode()?;
-        let items: Vec<MontyObject> = list
-            .into_iter()
-            .map(|t| decode_monty_object(env, t))
-            .collect::<NifResult<Vec<_>>>()?;
-        return Ok(MontyObject::List(items));
+    fn decode_pair<'a>(
+        &mut self,
+        env: Env<'a>,
+        key: Term<'a>,
+        value: Term<'a>,
+    ) -> NifResult<(MontyObject, MontyObject)> {
+        Ok((self.decode(env, key)?, self.decode(env, value)?))
     }
 
-    // Map - check for MapSet struct
-    if term.is_map() {
-        let struct_key = Atom::from_str(env, "__struct__").unwrap().encode(env);
-        if let Ok(struct_val) = term.map_get(struct_key) {
-            if let Ok(struct_name) = struct_val.atom_to_string() {
-                if struct_name == "Elixir.MapSet" {
-                    let map_key = Atom::from_str(env, "map").unwrap().encode(env);
-                    let inner_map = term.map_get(map_key).map_err(|_| rustler::Error::BadArg)?;
-                    let iter = MapIterator::new(inner_map).ok_or(rustler::Error::BadArg)?;
-                    let items: Vec<MontyObject> = iter
-                        .map(|(k, _v)| decode_monty_object(env, k))
-                        .collect::<NifResult<Vec<_>>>()?;
-                    return Ok(MontyObject::Set(items));
-                }
+    /// Decode a keyword list into a dict with string keys. As with
+    /// `Keyword.get/2`, the first value of a repeated key wins.
+    fn decode_keyword_list<'a>(
+        &mut self,
+        env: Env<'a>,
+        list: &[Term<'a>],
+    ) -> NifResult<MontyObject> {
+        let mut seen = HashSet::new();
+        let mut pairs = Vec::with_capacity(list.len());
+        for item in list {
+            let elems = get_tuple(*item)?;
+            let key = elems[0].atom_to_string()?;
+            if seen.insert(key.clone()) {
+                let value = self.at(format!("[:{key}]"), |d| d.decode(env, elems[1]))?;
+                pairs.push((MontyObject::String(key), value));
             }
         }
-        // Regular map → Dict
-        let iter = MapIterator::new(term).ok_or(rustler::Error::BadArg)?;
-        let pairs: Vec<(MontyObject, MontyObject)> = iter
+        Ok(MontyObject::dict(pairs))
+    }
+
+    /// Decode the members of a `MapSet` struct.
+    fn decode_mapset<'a>(&mut self, env: Env<'a>, term: Term<'a>) -> NifResult<Vec<MontyObject>> {
+        let inner_map = terms::map_get(term, "map").ok_or(rustler::Error::BadArg)?;
+        let iter = MapIterator::new(inner_map).ok_or(rustler::Error::BadArg)?;
+        iter.map(|(k, _v)| self.decode(env, k))
+            .collect::<NifResult<Vec<_>>>()
+    }
+
+    /// Decode an `%ExMonty.Dataclass{}` struct.
+    ///
+    /// Fields are set in `field_names` order; `fields` entries not named there are
+    /// appended as extra fields. Without `field_names` the order is that of the map.
+    /// `type_id` links the instance to its class in the interpreter; structs built
+    /// in Elixir leave it `nil`.
+    fn decode_dataclass<'a>(&mut self, env: Env<'a>, term: Term<'a>) -> NifResult<MontyObject> {
+        let field = |key: &str| terms::map_get(term, key).ok_or(rustler::Error::BadArg);
+
+        let name: String = field("name")?.decode()?;
+        let frozen: bool = field("frozen")?.decode()?;
+        let type_id = match field("type_id").ok().filter(|t| !is_nil(*t)) {
+            Some(t) => t.decode()?,
+            None => 0,
+        };
+        if type_id >= RESERVED_TYPE_IDS {
+            return Err(self.error(&format!("reserved dataclass type_id {type_id}")));
+        }
+
+        let iter = MapIterator::new(field("fields")?).ok_or(rustler::Error::BadArg)?;
+        let mut values: Vec<(String, Term)> = iter
+            .map(|(k, v)| Ok((k.decode::<String>()?, v)))
+            .collect::<NifResult<Vec<_>>>()?;
+
+        let mut field_names: Vec<String> = match field("field_names").ok().filter(|t| !is_nil(*t)) {
+            Some(t) => t.decode()?,
+            None => values.iter().map(|(k, _)| k.clone()).collect(),
+        };
+        values.sort_by_key(|(k, _)| {
+            field_names
+                .iter()
+                .position(|f| f == k)
+                .unwrap_or(field_names.len())
+        });
+        for (k, _) in &values {
+            if !field_names.contains(k) {
+                field_names.push(k.clone());
+            }
+        }
+
+        let attrs: Vec<(MontyObject, MontyObject)> = values
+            .into_iter()
             .map(|(k, v)| {
-                let key = decode_monty_object(env, k)?;
-                let val = decode_monty_object(env, v)?;
-                Ok((key, val))
+                let value = self.at(format!(".{k}"), |d| d.decode(env, v))?;
+                Ok((MontyObject::String(k), value))
             })
             .collect::<NifResult<Vec<_>>>()?;
-        return Ok(MontyObject::dict(pairs));
+
+        Ok(MontyObject::Dataclass {
+            name,
+            type_id,
+            field_names,
+            attrs: attrs.into(),
+            frozen,
+        })
     }
 
-    Err(rustler::Error::BadArg)
+    /// Decode the pair list of a tagged `{:dict, [{key, value}, ...]}`, keeping its order.
+    fn decode_dict_pairs<'a>(&mut self, env: Env<'a>, term: Term<'a>) -> NifResult<MontyObject> {
+        let items: Vec<Term> = term.decode()?;
+        let pairs: Vec<(MontyObject, MontyObject)> = items
+            .into_iter()
+            .map(|item| {
+                let elems = get_tuple(item)?;
+                if elems.len() != 2 {
+                    return Err(rustler::Error::BadArg);
+                }
+                self.at(key_segment(elems[0]), |d| {
+                    d.decode_pair(env, elems[0], elems[1])
+                })
+            })
+            .collect::<NifResult<Vec<_>>>()?;
+        Ok(MontyObject::dict(pairs))
+    }
+}
+
+/// Whether `list` is a non-empty keyword list: `{atom, value}` tuples only.
+fn is_keyword_list(list: &[Term]) -> bool {
+    !list.is_empty()
+        && list
+            .iter()
+            .all(|item| get_tuple(*item).is_ok_and(|elems| elems.len() == 2 && elems[0].is_atom()))
+}
+
+/// The string spelled by a non-empty list of printable Unicode code points,
+/// following `:io_lib.printable_unicode_list/1`.
+fn charlist_string(list: &[Term]) -> Option<String> {
+    if list.is_empty() {
+        return None;
+    }
+    list.iter()
+        .map(|item| {
+            let c = char::from_u32(item.decode::<u32>().ok()?)?;
+            let printable = matches!(c, '\u{8}'..='\u{d}' | '\u{1b}')
+                || (c >= ' ' && c != '\u{7f}' && !('\u{80}'..'\u{a0}').contains(&c));
+            printable.then_some(c)
+        })
+        .collect()
+}
+
+/// Path segment for the value under `key` in a map or dict.
+fn key_segment(key: Term) -> String {
+    if let Ok(s) = key.decode::<String>() {
+        format!("[{s:?}]")
+    } else if let Ok(name) = key.atom_to_string() {
+        format!("[:{name}]")
+    } else if let Ok(i) = key.decode::<i64>() {
+        format!("[{i}]")
+    } else {
+        "[...]".to_owned()
+    }
+}
+
+// ── Exceptions ───────────────────────────────────────────────────────────────
+
+impl Decoder<'_> {
+    /// Decode an `%ExMonty.Exception{}` returned by a handler as
+    /// `{:error, exception}` into the exception raised in Python.
+    pub fn decode_exception(&mut self, name: &str, term: Term) -> NifResult<MontyException> {
+        self.at(name.to_owned(), |d| {
+            let (exc_type, message) = d.decode_exception_parts(term)?;
+            Ok(MontyException::new(exc_type, message))
+        })
+    }
+
+    /// Decode the exception type of a handler's `{:error, type, message}`.
+    pub fn decode_exc_type_named(&mut self, name: &str, term: Term) -> NifResult<ExcType> {
+        self.at(name.to_owned(), |d| d.decode_exc_type(term))
+    }
+
+    /// Decode an `%ExMonty.Exception{}` struct into an exception type and
+    /// message. The interpreter's exceptions carry nothing else, so the
+    /// traceback and limit are ignored, and `args` may hold at most one
+    /// argument. When `message` is nil, it is `str()` of that argument.
+    fn decode_exception_parts(&self, term: Term) -> NifResult<(ExcType, Option<String>)> {
+        let field = |key: &str| terms::map_get(term, key);
+
+        let exc_type = match field("type") {
+            Some(t) => self.decode_exc_type(t)?,
+            None => return Err(self.error("exception without a type")),
+        };
+        let arg = match field("args") {
+            Some(args) => self.exception_arg(args)?,
+            None => None,
+        };
+        let message = match field("message") {
+            Some(message) if !is_nil(message) => Some(
+                message
+                    .decode()
+                    .map_err(|_| self.error("exception message is not a string"))?,
+            ),
+            _ => arg,
+        };
+        Ok((exc_type, message))
+    }
+
+    /// Decode an exception type given as an atom or string, in snake_case
+    /// (`:value_error`) or as the Python name (`"ValueError"`). Unknown
+    /// names are an error rather than a fallback to `RuntimeError`.
+    fn decode_exc_type(&self, term: Term) -> NifResult<ExcType> {
+        let (name, shown) = match term.atom_to_string() {
+            Ok(name) => (name.clone(), format!(":{name}")),
+            Err(_) => {
+                let name: String = term
+                    .decode()
+                    .map_err(|_| self.error("exception type is not an atom or string"))?;
+                (name.clone(), format!("{name:?}"))
+            }
+        };
+        ExcType::from_str(&name)
+            .or_else(|_| ExcType::from_str(&pascal_case(&name)))
+            .map_err(|_| self.error(&format!("unknown exception type {shown}")))
+    }
+
+    /// `str()` of the only argument in `args`, or `None` when it is empty.
+    /// An exception here has a single message, so more arguments are an
+    /// error rather than being joined into one string.
+    fn exception_arg(&self, term: Term) -> NifResult<Option<String>> {
+        let args: Vec<Term> = term
+            .decode()
+            .map_err(|_| self.error("exception args are not a list"))?;
+        match args.as_slice() {
+            [] => Ok(None),
+            [arg] => match arg.decode::<String>() {
+                Ok(s) => Ok(Some(s)),
+                Err(_) => arg_repr(*arg).map(Some).ok_or_else(|| {
+                    self.error("exception arg must be a string, number, boolean or nil")
+                }),
+            },
+            _ => Err(self.error("exceptions take at most one arg")),
+        }
+    }
+}
+
+/// Python repr of a plain exception argument.
+fn arg_repr(term: Term) -> Option<String> {
+    if let Ok(name) = term.atom_to_string() {
+        return match name.as_str() {
+            "nil" => Some("None".to_owned()),
+            "true" => Some("True".to_owned()),
+            "false" => Some("False".to_owned()),
+            _ => None,
+        };
+    }
+    if let Ok(i) = term.decode::<i64>() {
+        return Some(i.to_string());
+    }
+    if let Ok(bi) = term.decode::<BigInt>() {
+        return Some(bi.to_string());
+    }
+    if let Ok(f) = term.decode::<f64>() {
+        return Some(float_repr(f));
+    }
+    term.decode::<String>().ok().map(|s| str_repr(&s))
+}
+
+/// Python repr of a finite float: shortest round-trip digits, with an
+/// exponent outside `1e-4 <= |f| < 1e16`.
+fn float_repr(f: f64) -> String {
+    let abs = f.abs();
+    if abs != 0.0 && !(1e-4..1e16).contains(&abs) {
+        let s = format!("{f:e}");
+        let (mantissa, exp) = s.split_once('e').unwrap_or((&s, "0"));
+        let exp: i32 = exp.parse().unwrap_or(0);
+        let sign = if exp < 0 { '-' } else { '+' };
+        format!("{mantissa}e{sign}{:02}", exp.abs())
+    } else if f.fract() == 0.0 {
+        format!("{f:.1}")
+    } else {
+        format!("{f}")
+    }
+}
+
+/// Python repr of a string, quoted and escaped as CPython does.
+fn str_repr(s: &str) -> String {
+    let quote = if s.contains('\'') && !s.contains('"') {
+        '"'
+    } else {
+        '\''
+    };
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push(quote);
+    for c in s.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c == quote => {
+                out.push('\\');
+                out.push(c);
+            }
+            c if c < ' ' || c == '\u{7f}' => out.push_str(&format!("\\x{:02x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push(quote);
+    out
 }
 
 // ── Helper: Decode named inputs ──────────────────────────────────────────────
@@ -332,36 +884,44 @@ pub fn decode_inputs<'a>(
     env: Env<'a>,
     inputs: Vec<(String, Term<'a>)>,
     expected_input_names: &[String],
+    decoder: &mut Decoder,
 ) -> NifResult<Vec<MontyObject>> {
     if expected_input_names.is_empty() {
         if inputs.is_empty() {
             return Ok(Vec::new());
         }
 
-        return Err(rustler::Error::Term(Box::new(format!(
-            "unexpected inputs: expected none, got {}",
-            inputs.len()
-        ))));
+        let unexpected = inputs.into_iter().map(|(name, _)| name).collect::<Vec<_>>();
+        return Err(Error::new(
+            "input_error",
+            format!("unexpected inputs: expected none, got {}", unexpected.len()),
+        )
+        .detail("unexpected", Detail::Strings(unexpected))
+        .into());
     }
 
     let mut expected_set: HashSet<&str> = HashSet::with_capacity(expected_input_names.len());
     for name in expected_input_names {
         if !expected_set.insert(name.as_str()) {
-            return Err(rustler::Error::Term(Box::new(format!(
-                "runner has duplicate input name: {name}"
-            ))));
+            return Err(Error::new(
+                "internal_error",
+                format!("runner has duplicate input name: {name}"),
+            )
+            .into());
         }
     }
 
     let mut provided: HashMap<String, MontyObject> = HashMap::with_capacity(inputs.len());
     for (name, term) in inputs {
         if provided.contains_key(&name) {
-            return Err(rustler::Error::Term(Box::new(format!(
-                "duplicate input provided: {name}"
-            ))));
+            return Err(
+                Error::new("input_error", format!("duplicate input provided: {name}"))
+                    .detail("duplicate", Detail::Strings(vec![name]))
+                    .into(),
+            );
         }
 
-        let value = decode_monty_object(env, term)?;
+        let value = decoder.decode_named(env, &name, term)?;
         provided.insert(name, value);
     }
 
@@ -377,19 +937,24 @@ pub fn decode_inputs<'a>(
     }
 
     if !missing.is_empty() {
-        let missing_list = missing.into_iter().collect::<Vec<_>>().join(", ");
-        return Err(rustler::Error::Term(Box::new(format!(
-            "missing required inputs: {missing_list}"
-        ))));
+        let missing = missing.into_iter().map(str::to_owned).collect::<Vec<_>>();
+        return Err(Error::new(
+            "input_error",
+            format!("missing required inputs: {}", missing.join(", ")),
+        )
+        .detail("missing", Detail::Strings(missing))
+        .into());
     }
 
     if !provided.is_empty() {
         let mut unexpected = provided.keys().cloned().collect::<Vec<_>>();
         unexpected.sort();
-        return Err(rustler::Error::Term(Box::new(format!(
-            "unexpected inputs: {}",
-            unexpected.join(", ")
-        ))));
+        return Err(Error::new(
+            "input_error",
+            format!("unexpected inputs: {}", unexpected.join(", ")),
+        )
+        .detail("unexpected", Detail::Strings(unexpected))
+        .into());
     }
 
     Ok(ordered)
@@ -397,60 +962,201 @@ pub fn decode_inputs<'a>(
 
 // ── Helper: Decode ResourceLimits from Elixir map ────────────────────────────
 
-pub fn decode_resource_limits(term: Term) -> NifResult<ResourceLimits> {
-    if term.is_atom() {
-        let s = term.atom_to_string().map_err(|_| rustler::Error::BadArg)?;
-        if s == "nil" {
-            return Ok(ResourceLimits::new());
+/// Limits decoded from the Elixir limits map: the interpreter's own
+/// `ResourceLimits`, the session budget enforced by `ExTracker` and the output
+/// cap enforced by the print writer.
+pub struct Limits {
+    pub resource: ResourceLimits,
+    pub budget: Budget,
+    pub output: OutputBudget,
+}
+
+/// Decode limits from a map or keyword list.
+///
+/// Validation is strict: a misspelled key or a badly typed value would
+/// otherwise mean running without the intended limit. Unknown keys, duplicate
+/// keys and values that are not positive are rejected; `nil` leaves a limit
+/// unset, and integers are accepted where seconds are expected.
+pub fn decode_resource_limits(term: Term) -> NifResult<Limits> {
+    let mut limits = Limits {
+        resource: ResourceLimits::new(),
+        budget: Budget::default(),
+        output: OutputBudget::default(),
+    };
+
+    let mut max_output_bytes = None;
+    let mut output_policy = OutputPolicy::default();
+    let mut seen: HashSet<String> = HashSet::new();
+
+    for (key, val) in limit_entries(term)? {
+        let name = key.atom_to_string().map_err(|_| {
+            limit_error("limits", format!("limit keys must be atoms, got: {key:?}"))
+        })?;
+        if !seen.insert(name.clone()) {
+            return Err(limit_error(&name, format!("duplicate limit: {name}")));
+        }
+        if is_nil(val) {
+            continue;
+        }
+
+        match name.as_str() {
+            "max_allocations" => {
+                let n = positive_int(&name, val)?;
+                limits.resource = limits.resource.max_allocations(n);
+                limits.budget.max_allocations = Some(n);
+            }
+            "max_duration_secs" => {
+                limits.budget.max_duration = Some(positive_duration(&name, val)?);
+            }
+            "time_budget" => {
+                limits.budget.time_budget = match atom_choice(&name, val, &["cpu", "wall"])? {
+                    "cpu" => TimeBudget::Cpu,
+                    _ => TimeBudget::Wall,
+                };
+            }
+            "max_memory" => {
+                let n = positive_int(&name, val)?;
+                limits.resource = limits.resource.max_memory(n);
+                limits.budget.max_memory = Some(n);
+            }
+            "gc_interval" => {
+                limits.resource = limits.resource.gc_interval(positive_int(&name, val)?);
+            }
+            "max_recursion_depth" => {
+                let n = positive_int(&name, val)?;
+                limits.resource = limits.resource.max_recursion_depth(Some(n));
+            }
+            "max_output_bytes" => {
+                max_output_bytes = Some(positive_int(&name, val)?);
+            }
+            "output_limit_policy" => {
+                output_policy = match atom_choice(&name, val, &["error", "truncate"])? {
+                    "error" => OutputPolicy::Error,
+                    _ => OutputPolicy::Truncate,
+                };
+            }
+            other => return Err(limit_error(other, format!("unknown limit: {other}"))),
         }
     }
 
-    if !term.is_map() {
-        return Err(rustler::Error::BadArg);
+    limits.output = OutputBudget::new(max_output_bytes, output_policy);
+    Ok(limits)
+}
+
+fn limit_entries<'a>(term: Term<'a>) -> NifResult<Vec<(Term<'a>, Term<'a>)>> {
+    if is_nil(term) {
+        return Ok(Vec::new());
     }
 
-    let mut limits = ResourceLimits::new();
-    let env = term.get_env();
+    if term.is_map() {
+        let iter = MapIterator::new(term).ok_or(rustler::Error::BadArg)?;
+        return Ok(iter.collect());
+    }
 
-    if let Ok(val) = term.map_get(Atom::from_str(env, "max_allocations").unwrap().encode(env)) {
-        if let Ok(n) = val.decode::<usize>() {
-            limits = limits.max_allocations(n);
+    if term.is_list() {
+        if let Ok(items) = term.decode::<Vec<Term>>() {
+            let pairs = items
+                .into_iter()
+                .map(|item| match get_tuple(item) {
+                    Ok(elems) if elems.len() == 2 => Some((elems[0], elems[1])),
+                    _ => None,
+                })
+                .collect::<Option<Vec<_>>>();
+            if let Some(pairs) = pairs {
+                return Ok(pairs);
+            }
         }
     }
 
-    if let Ok(val) = term.map_get(
-        Atom::from_str(env, "max_duration_secs")
-            .unwrap()
-            .encode(env),
-    ) {
-        if let Ok(secs) = val.decode::<f64>() {
-            limits = limits.max_duration(Duration::from_secs_f64(secs));
-        }
+    Err(limit_error(
+        "limits",
+        format!("limits must be a map or keyword list, got: {term:?}"),
+    ))
+}
+
+fn positive_int(name: &str, val: Term) -> NifResult<usize> {
+    match val.decode::<usize>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(limit_error(
+            name,
+            format!("invalid {name}: expected a positive integer, got: {val:?}"),
+        )),
     }
+}
 
-    if let Ok(val) = term.map_get(Atom::from_str(env, "max_memory").unwrap().encode(env)) {
-        if let Ok(n) = val.decode::<usize>() {
-            limits = limits.max_memory(n);
-        }
+fn positive_duration(name: &str, val: Term) -> NifResult<Duration> {
+    let secs = if val.is_float() {
+        val.decode::<f64>().ok()
+    } else {
+        val.decode::<i64>().ok().map(|n| n as f64)
+    };
+
+    match secs.map(Duration::try_from_secs_f64) {
+        Some(Ok(duration)) if !duration.is_zero() => Ok(duration),
+        _ => Err(limit_error(
+            name,
+            format!("invalid {name}: expected a positive number of seconds, got: {val:?}"),
+        )),
     }
+}
 
-    if let Ok(val) = term.map_get(Atom::from_str(env, "gc_interval").unwrap().encode(env)) {
-        if let Ok(n) = val.decode::<usize>() {
-            limits = limits.gc_interval(n);
-        }
+fn atom_choice<'c>(name: &str, val: Term, choices: &[&'c str]) -> NifResult<&'c str> {
+    let atom = val.atom_to_string().ok();
+    choices
+        .iter()
+        .find(|choice| atom.as_deref() == Some(**choice))
+        .copied()
+        .ok_or_else(|| {
+            limit_error(
+                name,
+                format!(
+                    "invalid {name}: expected one of :{}, got: {val:?}",
+                    choices.join(", :")
+                ),
+            )
+        })
+}
+
+/// An `:invalid_option` error for the option or limit `name`.
+fn limit_error(name: &str, message: String) -> rustler::Error {
+    Error::invalid_option(name, message).into()
+}
+
+fn is_nil(term: Term) -> bool {
+    term.is_atom() && term.atom_to_string().ok().as_deref() == Some("nil")
+}
+
+// ── Helper: Read per-call options from Elixir map ────────────────────────────
+
+/// Look up `key` in the options map passed to a NIF. Missing keys, `nil`
+/// values and a `nil` options map are all treated as "not set".
+pub fn get_option<'a>(opts: Term<'a>, key: &str) -> NifResult<Option<Term<'a>>> {
+    if is_nil(opts) {
+        return Ok(None);
     }
 
-    if let Ok(val) = term.map_get(
-        Atom::from_str(env, "max_recursion_depth")
-            .unwrap()
-            .encode(env),
-    ) {
-        if let Ok(n) = val.decode::<usize>() {
-            limits = limits.max_recursion_depth(Some(n));
-        }
+    if !opts.is_map() {
+        return Err(rustler::Error::BadArg);
     }
 
-    Ok(limits)
+    match terms::map_get(opts, key) {
+        Some(val) if is_nil(val) => Ok(None),
+        other => Ok(other),
+    }
+}
+
+/// Read a boolean flag from the options map; unset means `false`.
+pub fn get_flag(opts: Term, key: &str) -> NifResult<bool> {
+    match get_option(opts, key)? {
+        Some(val) => val.decode::<bool>().map_err(|_| {
+            Error::invalid_option(
+                key,
+                format!("invalid {key}: expected a boolean, got: {val:?}"),
+            )
+            .into()
+        }),
+        None => Ok(false),
+    }
 }
 
 pub fn encode_os_function<'a>(env: Env<'a>, func: &OsFunction) -> Term<'a> {
@@ -474,87 +1180,90 @@ pub fn encode_os_function<'a>(env: Env<'a>, func: &OsFunction) -> Term<'a> {
         OsFunction::Getenv => "getenv",
         OsFunction::GetEnviron => "get_environ",
     };
-    Atom::from_str(env, name).unwrap().encode(env)
+    terms::atom(env, name)
 }
 
-fn decode_named_tuple<'a>(
-    env: Env<'a>,
-    type_term: Term<'a>,
-    fields_term: Term<'a>,
-) -> NifResult<MontyObject> {
-    let raw_type_name: String = if type_term.is_atom() {
-        type_term
-            .atom_to_string()
-            .map_err(|_| rustler::Error::BadArg)?
-    } else if type_term.is_binary() {
-        type_term.decode()?
-    } else {
-        return Err(rustler::Error::BadArg);
-    };
+impl Decoder<'_> {
+    fn decode_named_tuple<'a>(
+        &mut self,
+        env: Env<'a>,
+        type_term: Term<'a>,
+        fields_term: Term<'a>,
+    ) -> NifResult<MontyObject> {
+        let raw_type_name: String = if type_term.is_atom() {
+            type_term
+                .atom_to_string()
+                .map_err(|_| rustler::Error::BadArg)?
+        } else if type_term.is_binary() {
+            type_term.decode()?
+        } else {
+            return Err(rustler::Error::BadArg);
+        };
 
-    let type_name = normalize_namedtuple_type_name(&raw_type_name);
+        let type_name = normalize_namedtuple_type_name(&raw_type_name);
 
-    // Prefer order-preserving list-of-pairs representation.
-    if fields_term.is_list() {
-        let fields: Vec<Term> = fields_term.decode()?;
-        let mut field_names = Vec::with_capacity(fields.len());
-        let mut values = Vec::with_capacity(fields.len());
+        // Prefer order-preserving list-of-pairs representation.
+        if fields_term.is_list() {
+            let fields: Vec<Term> = fields_term.decode()?;
+            let mut field_names = Vec::with_capacity(fields.len());
+            let mut values = Vec::with_capacity(fields.len());
 
-        for item in fields {
-            let elems = get_tuple(item).map_err(|_| rustler::Error::BadArg)?;
-            if elems.len() != 2 {
-                return Err(rustler::Error::BadArg);
-            }
+            for item in fields {
+                let elems = get_tuple(item).map_err(|_| rustler::Error::BadArg)?;
+                if elems.len() != 2 {
+                    return Err(rustler::Error::BadArg);
+                }
 
-            let field_name: String = if elems[0].is_atom() {
-                elems[0]
-                    .atom_to_string()
-                    .map_err(|_| rustler::Error::BadArg)?
-            } else if elems[0].is_binary() {
-                elems[0].decode()?
-            } else {
-                return Err(rustler::Error::BadArg);
-            };
+                let field_name: String = if elems[0].is_atom() {
+                    elems[0]
+                        .atom_to_string()
+                        .map_err(|_| rustler::Error::BadArg)?
+                } else if elems[0].is_binary() {
+                    elems[0].decode()?
+                } else {
+                    return Err(rustler::Error::BadArg);
+                };
+
+                let value = self.at(format!(".{field_name}"), |d| d.decode(env, elems[1]))?;
+                field_names.push(field_name);
+                values.push(value);
+            }
 
-            let value = decode_monty_object(env, elems[1])?;
-            field_names.push(field_name);
-            values.push(value);
+            return Ok(MontyObject::NamedTuple {
+                type_name,
+                field_names,
+                values,
+            });
         }
 
-        return Ok(MontyObject::NamedTuple {
-            type_name,
-            field_names,
-            values,
-        });
-    }
+        if fields_term.is_map() {
+            let iter = MapIterator::new(fields_term).ok_or(rustler::Error::BadArg)?;
 
-    if fields_term.is_map() {
-        let iter = MapIterator::new(fields_term).ok_or(rustler::Error::BadArg)?;
+            let mut by_name: HashMap<String, MontyObject> = HashMap::new();
+            for (k, v) in iter {
+                let field_name: String = if k.is_atom() {
+                    k.atom_to_string().map_err(|_| rustler::Error::BadArg)?
+                } else if k.is_binary() {
+                    k.decode()?
+                } else {
+                    return Err(rustler::Error::BadArg);
+                };
 
-        let mut by_name: HashMap<String, MontyObject> = HashMap::new();
-        for (k, v) in iter {
-            let field_name: String = if k.is_atom() {
-                k.atom_to_string().map_err(|_| rustler::Error::BadArg)?
-            } else if k.is_binary() {
-                k.decode()?
-            } else {
-                return Err(rustler::Error::BadArg);
-            };
+                let value = self.at(format!(".{field_name}"), |d| d.decode(env, v))?;
+                by_name.insert(field_name, value);
+            }
 
-            let value = decode_monty_object(env, v)?;
-            by_name.insert(field_name, value);
-        }
+            let (field_names, values) = order_named_tuple_fields(&type_name, by_name)?;
 
-        let (field_names, values) = order_named_tuple_fields(&type_name, by_name)?;
+            return Ok(MontyObject::NamedTuple {
+                type_name,
+                field_names,
+                values,
+            });
+        }
 
-        return Ok(MontyObject::NamedTuple {
-            type_name,
-            field_names,
-            values,
-        });
+        Err(rustler::Error::BadArg)
     }
-
-    Err(rustler::Error::BadArg)
 }
 
 fn normalize_namedtuple_type_name(s: &str) -> String {
@@ -588,13 +1297,9 @@ fn order_named_tuple_fields(
         return Ok((field_names, values));
     }
 
-    let mut field_names = by_name.keys().cloned().collect::<Vec<_>>();
-    field_names.sort();
-    let values = field_names
-        .iter()
-        .map(|name| by_name.remove(name).unwrap())
-        .collect();
-    Ok((field_names, values))
+    let mut fields = by_name.into_iter().collect::<Vec<_>>();
+    fields.sort_by(|(a, _), (b, _)| a.cmp(b));
+    Ok(fields.into_iter().unzip())
 }
 
 fn pascal_case(s: &str) -> String {
@@ -0,0 +1,90 @@
+defmodule ExMonty.AsyncTest do
+  use ExUnit.Case
+
+  test "run_async sends the result as a message" do
+    {:ok, runner} = ExMonty.compile("print('hi')\nx + 1", inputs: ["x"])
+    {:ok, ref} = ExMonty.run_async(runner, %{"x" => 41})
+
+    assert_receive {:ex_monty_result, ^ref, {:ok, 42, "hi\n"}}, 1000
+  end
+
+  test "run_async reports script errors" do
+    {:ok, runner} = ExMonty.compile("1 / 0")
+    {:ok, ref} = ExMonty.run_async(runner)
+
+    assert {:error, %ExMonty.Exception{type: :zero_division_error}} = ExMonty.await(ref, 1000)
+  end
+
+  test "run_async supports stats" do
+    {:ok, runner} = ExMonty.compile("[i for i in range(10)]")
+    {:ok, ref} = ExMonty.run_async(runner, %{}, stats: true)
+
+    assert {:ok, _result, "", %{allocations: _}} = ExMonty.await(ref, 1000)
+  end
+
+  test "invalid limits fail the call itself" do
+    {:ok, runner} = ExMonty.compile("1")
+
+    assert {:error,
+            %ExMonty.Error{kind: :invalid_option, message: "unknown limit: max_memmory"}} =
+             ExMonty.run_async(runner, %{}, limits: %{max_memmory: 10})
+  end
+
+  test "inputs are decoded on the pool" do
+    {:ok, runner} = ExMonty.compile("x", inputs: ["x"])
+    {:ok, ref} = ExMonty.run_async(runner, %{"x" => [1 | 2]})
+
+    assert {:error, %ExMonty.Error{kind: :input_error, details: %{path: "x"}}} =
+             ExMonty.await(ref, 1000)
+  end
+
+  test "interactive execution on the pool" do
+    {:ok, runner} = ExMonty.compile("fetch(1) + 1", external_functions: ["fetch"])
+
+    {:ok, ref} = ExMonty.start_async(runner)
+    assert {:ok, {:function_call, %{name: "fetch"}, snapshot, ""}} = ExMonty.await(ref, 1000)
+
+    {:ok, ref} = ExMonty.resume_async(snapshot, {:ok, 10})
+    assert {:ok, {:complete, 11, ""}} = ExMonty.await(ref, 1000)
+
+    assert {:error,
+            %ExMonty.Error{kind: :consumed_snapshot, message: "snapshot already consumed"}} =
+             ExMonty.resume_async(snapshot, {:ok, 10})
+  end
+
+  test "await times out" do
+    assert {:error, :timeout} = ExMonty.await(make_ref(), 10)
+  end
+
+  test "pool size can be changed" do
+    original = ExMonty.async_pool_size()
+    assert original > 0
+
+    try do
+      assert :ok = ExMonty.set_async_pool_size(2)
+      assert ExMonty.async_pool_size() == 2
+
+      {:ok, runner} = ExMonty.compile("x * 2", inputs: ["x"])
+      refs = for x <- 1..10, do: elem(ExMonty.run_async(runner, %{"x" => x}), 1)
+      assert Enum.map(refs, &ExMonty.await(&1, 1000)) == for(x <- 1..10, do: {:ok, x * 2, ""})
+
+      assert :ok = ExMonty.set_async_pool_size(1)
+      assert :ok = ExMonty.set_async_pool_size(3)
+      assert ExMonty.async_pool_size() == 3
+
+      refs = for x <- 1..10, do: elem(ExMonty.run_async(runner, %{"x" => x}), 1)
+      assert Enum.map(refs, &ExMonty.await(&1, 1000)) == for(x <- 1..10, do: {:ok, x * 2, ""})
+    after
+      ExMonty.set_async_pool_size(original)
+    end
+  end
+
+  test "async runs can be cancelled" do
+    {:ok, runner} = ExMonty.compile("while True:\n    pass")
+    token = ExMonty.cancel_token()
+    {:ok, ref} = ExMonty.run_async(runner, %{}, cancel: token)
+
+    ExMonty.cancel(token)
+    assert {:error, %ExMonty.Error{kind: :cancelled}} = ExMonty.await(ref, 1000)
+  end
+end
@@ -0,0 +1,68 @@
+defmodule ExMonty.BatchTest do
+  use ExUnit.Case
+
+  setup do
+    {:ok, runner} = ExMonty.compile("print(x)\n10 // x", inputs: ["x"])
+    %{runner: runner}
+  end
+
+  test "runs each input map", %{runner: runner} do
+    assert {:ok, [{:ok, 10, "1\n"}, {:ok, 5, "2\n"}]} =
+             ExMonty.run_batch(runner, [%{"x" => 1}, %{x: 2}])
+  end
+
+  test "a failing item does not abort the batch", %{runner: runner} do
+    assert {:ok, [{:ok, 10, _}, {:error, exc}, {:error, _missing}, {:ok, 2, _}]} =
+             ExMonty.run_batch(runner, [%{"x" => 1}, %{"x" => 0}, %{}, %{"x" => 5}])
+
+    assert %ExMonty.Exception{type: :zero_division_error} = exc
+  end
+
+  test "an item that is not an input map only fails itself", %{runner: runner} do
+    assert {:ok, [{:ok, 10, _}, {:error, not_a_map}, {:error, bad_key}, {:ok, 2, _}]} =
+             ExMonty.run_batch(runner, [%{"x" => 1}, 42, %{{:x} => 1}, %{"x" => 5}])
+
+    assert %ExMonty.Error{kind: :input_error, details: %{path: "batch[1]"}} = not_a_map
+    assert %ExMonty.Error{kind: :input_error, details: %{path: "batch[2]"}} = bad_key
+  end
+
+  test "parallel results keep their order", %{runner: runner} do
+    batch = for x <- 1..50, do: %{"x" => x}
+    {:ok, results} = ExMonty.run_batch(runner, batch, max_concurrency: 4)
+
+    assert Enum.map(results, &elem(&1, 1)) == for(x <- 1..50, do: div(10, x))
+  end
+
+  test "limits apply to each item" do
+    {:ok, runner} = ExMonty.compile("while n > 0:\n    n -= 1", inputs: ["n"])
+
+    {:ok, [fast, slow]} =
+      ExMonty.run_batch(runner, [%{"n" => 1}, %{"n" => 10_000_000_000}],
+        limits: %{max_duration_secs: 0.1}
+      )
+
+    assert {:ok, nil, ""} = fast
+    assert {:error, %ExMonty.Exception{limit: {:time_limit, _, _}}} = slow
+  end
+
+  test "stats per item", %{runner: runner} do
+    assert {:ok, [{:ok, 10, _, %{allocations: _}}]} =
+             ExMonty.run_batch(runner, [%{"x" => 1}], stats: true)
+  end
+
+  test "rejects invalid options", %{runner: runner} do
+    assert {:error,
+            %ExMonty.Error{
+              kind: :invalid_option,
+              message: "max_concurrency must be a positive integer"
+            }} =
+             ExMonty.run_batch(runner, [%{"x" => 1}], max_concurrency: 0)
+
+    assert {:error, %ExMonty.Error{kind: :invalid_option, message: "unknown limit: foo"}} =
+             ExMonty.run_batch(runner, [%{"x" => 1}], limits: %{foo: 1})
+  end
+
+  test "empty batch", %{runner: runner} do
+    assert {:ok, []} = ExMonty.run_batch(runner, [])
+  end
+end
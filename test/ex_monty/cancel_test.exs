@@ -0,0 +1,100 @@
+defmodule ExMonty.CancelTest do
+  use ExUnit.Case
+
+  @loop """
+  i = 0
+  while True:
+      i += 1
+  """
+
+  test "cancel stops a running script" do
+    {:ok, runner} = ExMonty.compile(@loop)
+    token = ExMonty.cancel_token()
+    task = Task.async(fn -> ExMonty.run(runner, %{}, cancel: token) end)
+
+    Process.sleep(50)
+    assert :ok = ExMonty.cancel(token)
+    assert {:error, %ExMonty.Error{kind: :cancelled}} = Task.await(task)
+    assert ExMonty.cancelled?(token)
+  end
+
+  test "scripts cannot catch cancellation" do
+    code = """
+    while True:
+        try:
+            while True:
+                pass
+        except Exception:
+            pass
+    """
+
+    {:ok, runner} = ExMonty.compile(code)
+    token = ExMonty.cancel_token()
+    task = Task.async(fn -> ExMonty.run(runner, %{}, cancel: token) end)
+
+    Process.sleep(50)
+    ExMonty.cancel(token)
+    assert {:error, %ExMonty.Error{kind: :cancelled}} = Task.await(task)
+  end
+
+  test "an uncancelled token does not affect the result" do
+    token = ExMonty.cancel_token()
+    assert {:ok, 4, ""} = ExMonty.eval("2 + 2", cancel: token)
+    refute ExMonty.cancelled?(token)
+  end
+
+  test "token cancelled before resume stops the next step" do
+    code = """
+    fetch()
+    i = 0
+    while True:
+        i += 1
+    """
+
+    {:ok, runner} = ExMonty.compile(code, external_functions: ["fetch"])
+    token = ExMonty.cancel_token()
+
+    {:ok, {:function_call, _call, snapshot, _output}} =
+      ExMonty.start(runner, %{}, cancel: token)
+
+    ExMonty.cancel(token)
+    assert {:error, %ExMonty.Error{kind: :cancelled}} =
+             ExMonty.resume(snapshot, {:ok, nil}, cancel: token)
+  end
+
+  test "token is cancelled when its owner exits" do
+    owner = spawn(fn -> receive do: (:stop -> :ok) end)
+    token = ExMonty.cancel_token(owner: owner)
+    refute ExMonty.cancelled?(token)
+
+    ref = Process.monitor(owner)
+    send(owner, :stop)
+    assert_receive {:DOWN, ^ref, :process, ^owner, _}
+
+    Process.sleep(10)
+    assert ExMonty.cancelled?(token)
+  end
+
+  test "Sandbox.run passes the token to every step" do
+    token = ExMonty.cancel_token()
+
+    code = """
+    fetch()
+    while True:
+        pass
+    """
+
+    result =
+      ExMonty.Sandbox.run(code,
+        functions: %{
+          "fetch" => fn _args, _kwargs ->
+            ExMonty.cancel(token)
+            {:ok, nil}
+          end
+        },
+        cancel: token
+      )
+
+    assert {:error, %ExMonty.Error{kind: :cancelled}} = result
+  end
+end
@@ -0,0 +1,53 @@
+defmodule ExMonty.ExceptionTest do
+  use ExUnit.Case
+
+  alias ExMonty.{Exception, StackFrame}
+
+  describe "format/1" do
+    test "matches CPython's traceback layout" do
+      exception = %Exception{
+        type: :zero_division_error,
+        message: "division by zero",
+        traceback: [
+          %StackFrame{filename: "main.py", line: 4, name: nil, source: "divide(1)", caret: {1, 9}},
+          %StackFrame{
+            filename: "main.py",
+            line: 2,
+            name: "divide",
+            source: "    return x / 0",
+            caret: {12, 16}
+          }
+        ]
+      }
+
+      assert Exception.format(exception) == """
+             Traceback (most recent call last):
+               File "main.py", line 4, in <module>
+                 divide(1)
+               File "main.py", line 2, in divide
+                 return x / 0
+                        ^^^^^
+             ZeroDivisionError: division by zero
+             """
+    end
+
+    test "frames without source show only their location" do
+      exception = %Exception{
+        type: :value_error,
+        message: "bad",
+        traceback: [%StackFrame{filename: "main.py", line: 1}]
+      }
+
+      assert Exception.format(exception) == """
+             Traceback (most recent call last):
+               File "main.py", line 1, in <module>
+             ValueError: bad
+             """
+    end
+
+    test "exception values format as the last line only" do
+      assert Exception.format(%Exception{type: :type_error, message: nil}) == "TypeError\n"
+      assert Exception.format(%Exception{type: :key_error, message: "'k'"}) == "KeyError: 'k'\n"
+    end
+  end
+end
@@ -64,6 +64,74 @@ defmodule ExMonty.InteractiveTest do
       assert {:complete, "connection failed", _} = final
     end
 
+    test "function call with exception struct error" do
+      code = """
+      try:
+          result = fetch('bad_url')
+      except ValueError as e:
+          result = str(e)
+      result
+      """
+
+      {:ok, runner} = ExMonty.compile(code, external_functions: ["fetch"])
+
+      cases = [
+        {[type: :value_error, args: [42]], "42"},
+        {[type: "ValueError", args: ["only"]], "only"},
+        {[type: :value_error, message: "msg", args: ["msg"]], "msg"}
+      ]
+
+      for {fields, message} <- cases do
+        {:ok, {:function_call, _call, snapshot, _}} = ExMonty.start(runner)
+        error = struct!(ExMonty.Exception, fields)
+        assert {:ok, {:complete, ^message, _}} = ExMonty.resume(snapshot, {:error, error})
+      end
+    end
+
+    test "malformed error responses are input errors" do
+      {:ok, runner} = ExMonty.compile("fetch()", external_functions: ["fetch"])
+      {:ok, {:function_call, _call, snapshot, _}} = ExMonty.start(runner)
+
+      cases = [
+        {{:error, %ExMonty.Exception{type: :value_error, args: ["bad", 42]}},
+         "exceptions take at most one arg at result"},
+        {{:error, :value_error, 42}, "exception message is not a string at result"},
+        {{:error, "no type"},
+         "expected {:error, type, message} or {:error, %ExMonty.Exception{}} at result"},
+        {{:error, :value_error, "msg", :extra},
+         "expected {:error, type, message} or {:error, %ExMonty.Exception{}} at result"}
+      ]
+
+      for {result, message} <- cases do
+        assert {:error, %ExMonty.Error{kind: :input_error, message: ^message}} =
+                 ExMonty.resume(snapshot, result)
+      end
+
+      assert {:ok, {:complete, 1, _}} = ExMonty.resume(snapshot, {:ok, 1})
+    end
+
+    test "unknown exception type in error response is a decode error" do
+      {:ok, runner} = ExMonty.compile("fetch()", external_functions: ["fetch"])
+      {:ok, {:function_call, _call, snapshot, _}} = ExMonty.start(runner)
+
+      assert {:error,
+              %ExMonty.Error{
+                kind: :input_error,
+                message: "unknown exception type :no_such_error at result"
+              }} =
+               ExMonty.resume(snapshot, {:error, :no_such_error, "boom"})
+
+      assert {:error,
+              %ExMonty.Error{
+                kind: :input_error,
+                message: "unknown exception type \"Bogus\" at result"
+              }} =
+               ExMonty.resume(snapshot, {:error, %ExMonty.Exception{type: "Bogus"}})
+
+      # The snapshot is not consumed by a failed decode.
+      assert {:ok, {:complete, 1, _}} = ExMonty.resume(snapshot, {:ok, 1})
+    end
+
     test "function call with kwargs" do
       code = """
       result = fetch('url', timeout=30)
@@ -95,7 +163,29 @@ defmodule ExMonty.InteractiveTest do
       {:ok, _} = ExMonty.resume(snapshot, {:ok, "result"})
 
       # Second resume should fail - snapshot consumed
-      assert {:error, _} = ExMonty.resume(snapshot, {:ok, "result2"})
+      assert {:error, %ExMonty.Error{kind: :consumed_snapshot}} =
+               ExMonty.resume(snapshot, {:ok, "result2"})
+    end
+
+    test "print_to streams output from start and resume" do
+      code = """
+      print('before')
+      fetch('url')
+      print('after')
+      """
+
+      {:ok, runner} = ExMonty.compile(code, external_functions: ["fetch"])
+      ref = make_ref()
+
+      {:ok, {:function_call, _call, snapshot, "before\n"}} =
+        ExMonty.start(runner, %{}, print_to: {self(), ref})
+
+      assert_received {:ex_monty_print, ^ref, "before\n"}
+
+      {:ok, {:complete, nil, "after\n"}} =
+        ExMonty.resume(snapshot, {:ok, nil}, print_to: {self(), ref})
+
+      assert_received {:ex_monty_print, ^ref, "after\n"}
     end
 
     test "start with resource limits" do
@@ -104,4 +194,23 @@ defmodule ExMonty.InteractiveTest do
       assert {:complete, 4, _} = progress
     end
   end
+
+  describe "handles" do
+    test "a handle returned by an external function comes back in later calls" do
+      code = """
+      conn = connect()
+      query(conn, 'select 1')
+      """
+
+      {:ok, runner} = ExMonty.compile(code, external_functions: ["connect", "query"])
+      {:ok, {:function_call, %{name: "connect"}, snapshot, _}} = ExMonty.start(runner)
+
+      conn = make_ref()
+
+      assert {:ok, {:function_call, call, snapshot, _}} = ExMonty.resume(snapshot, {:ok, conn})
+      assert %{name: "query", args: [^conn, "select 1"]} = call
+
+      assert {:ok, {:complete, [1], _}} = ExMonty.resume(snapshot, {:ok, [1]})
+    end
+  end
 end
@@ -11,7 +11,8 @@ defmodule ExMonty.LimitsTest do
 
       {:ok, runner} = ExMonty.compile(code)
       result = ExMonty.run(runner, %{}, limits: %{max_duration_secs: 0.1})
-      assert {:error, _} = result
+      assert {:error, %ExMonty.Exception{limit: {:time_limit, 0.1, elapsed}}} = result
+      assert elapsed >= 0.1
     end
 
     test "recursion limit" do
@@ -24,7 +25,7 @@ defmodule ExMonty.LimitsTest do
 
       {:ok, runner} = ExMonty.compile(code)
       result = ExMonty.run(runner, %{}, limits: %{max_recursion_depth: 50})
-      assert {:error, _} = result
+      assert {:error, %ExMonty.Exception{limit: {:recursion_limit, 50, _depth}}} = result
     end
 
     test "allocation limit" do
@@ -36,11 +37,294 @@ defmodule ExMonty.LimitsTest do
 
       {:ok, runner} = ExMonty.compile(code)
       result = ExMonty.run(runner, %{}, limits: %{max_allocations: 10, max_memory: 500})
-      assert {:error, _} = result
+
+      assert {:error, %ExMonty.Exception{kind: :limit_exceeded, limit: {limit, _, used}}} =
+               result
+
+      assert limit in [:allocation_limit, :memory_limit]
+      assert used > 0
+    end
+
+    test "a caught limit is not reported with a later exception" do
+      code = """
+      def infinite():
+          return infinite()
+
+      try:
+          infinite()
+      except RecursionError:
+          pass
+      raise ValueError('later')
+      """
+
+      {:ok, runner} = ExMonty.compile(code)
+      result = ExMonty.run(runner, %{}, limits: %{max_recursion_depth: 50})
+
+      assert {:error,
+              %ExMonty.Exception{kind: :python_exception, type: :value_error, limit: nil}} =
+               result
+    end
+
+    test "output limit stops execution by default" do
+      code = """
+      while True:
+          print('x' * 100)
+      """
+
+      {:ok, runner} = ExMonty.compile(code)
+      result = ExMonty.run(runner, %{}, limits: %{max_output_bytes: 1000})
+      assert {:error, %ExMonty.Exception{limit: {:output_limit, 1000, written}}} = result
+      assert written > 1000
+    end
+
+    test "output limit with truncate policy keeps running" do
+      code = """
+      for i in range(100):
+          print('x' * 100)
+      'done'
+      """
+
+      {:ok, runner} = ExMonty.compile(code)
+
+      {:ok, "done", output} =
+        ExMonty.run(runner, %{},
+          limits: %{max_output_bytes: 250, output_limit_policy: :truncate}
+        )
+
+      assert String.starts_with?(output, String.duplicate("x", 100) <> "\n")
+      assert String.ends_with?(output, "[output truncated]\n")
+      assert byte_size(output) < 300
+    end
+
+    test "output limit covers resume steps" do
+      code = """
+      print('x' * 60)
+      fetch()
+      print('x' * 60)
+      """
+
+      {:ok, runner} = ExMonty.compile(code, external_functions: ["fetch"])
+
+      {:ok, {:function_call, _call, snapshot, _}} =
+        ExMonty.start(runner, %{}, limits: %{max_output_bytes: 100})
+
+      assert {:error, %ExMonty.Exception{limit: {:output_limit, 100, _}}} =
+               ExMonty.resume(snapshot, {:ok, nil})
+    end
+
+    test "script errors have no limit" do
+      {:ok, runner} = ExMonty.compile("1 / 0")
+      result = ExMonty.run(runner, %{}, limits: %{max_duration_secs: 5.0})
+      assert {:error, %ExMonty.Exception{type: :zero_division_error, limit: nil}} = result
+    end
+
+    test "limit hit during resume is structured" do
+      code = """
+      fetch()
+      while True:
+          pass
+      """
+
+      {:ok, runner} = ExMonty.compile(code, external_functions: ["fetch"])
+
+      {:ok, {:function_call, _call, snapshot, _}} =
+        ExMonty.start(runner, %{}, limits: %{max_duration_secs: 0.1})
+
+      assert {:error, %ExMonty.Exception{limit: {:time_limit, _, _}}} =
+               ExMonty.resume(snapshot, {:ok, nil})
     end
 
     test "default limits allow normal code" do
       assert {:ok, 42, ""} = ExMonty.eval("42")
     end
   end
+
+  describe "limit validation" do
+    setup do
+      {:ok, runner} = ExMonty.compile("1 + 1")
+      %{runner: runner}
+    end
+
+    test "unknown keys are rejected", %{runner: runner} do
+      assert {:error,
+              %ExMonty.Error{kind: :invalid_option, message: "unknown limit: max_memmory"}} =
+               ExMonty.run(runner, %{}, limits: %{max_memmory: 1000})
+    end
+
+    test "integer seconds are accepted", %{runner: runner} do
+      assert {:ok, 2, ""} = ExMonty.run(runner, %{}, limits: %{max_duration_secs: 5})
+    end
+
+    test "zero and negative values are rejected", %{runner: runner} do
+      assert {:error,
+              %ExMonty.Error{kind: :invalid_option, message: "invalid max_memory: " <> _}} =
+               ExMonty.run(runner, %{}, limits: %{max_memory: 0})
+
+      assert {:error,
+              %ExMonty.Error{
+                kind: :invalid_option,
+                message: "invalid max_duration_secs: " <> _
+              }} =
+               ExMonty.run(runner, %{}, limits: %{max_duration_secs: -1.0})
+    end
+
+    test "wrong-typed values are rejected", %{runner: runner} do
+      assert {:error,
+              %ExMonty.Error{kind: :invalid_option, message: "invalid max_allocations: " <> _}} =
+               ExMonty.run(runner, %{}, limits: %{max_allocations: "100"})
+
+      assert {:error,
+              %ExMonty.Error{
+                kind: :invalid_option,
+                message: "invalid output_limit_policy: " <> _
+              }} =
+               ExMonty.run(runner, %{}, limits: %{output_limit_policy: :ignore})
+    end
+
+    test "keyword lists are accepted", %{runner: runner} do
+      assert {:ok, 2, ""} =
+               ExMonty.run(runner, %{}, limits: [max_memory: 1_000_000, max_duration_secs: 1])
+    end
+
+    test "duplicate keyword keys are rejected", %{runner: runner} do
+      assert {:error,
+              %ExMonty.Error{kind: :invalid_option, message: "duplicate limit: max_memory"}} =
+               ExMonty.run(runner, %{}, limits: [max_memory: 1000, max_memory: 2000])
+    end
+
+    test "non-map limits are rejected", %{runner: runner} do
+      assert {:error,
+              %ExMonty.Error{
+                kind: :invalid_option,
+                message: "limits must be a map or keyword list" <> _
+              }} =
+               ExMonty.run(runner, %{}, limits: 5)
+    end
+  end
+
+  describe "session budget" do
+    test "cpu budget ignores time spent between steps" do
+      {:ok, runner} = ExMonty.compile("fetch()\nfetch()", external_functions: ["fetch"])
+      limits = %{max_duration_secs: 0.2, time_budget: :cpu}
+
+      {:ok, {:function_call, _, snap1, _}} = ExMonty.start(runner, %{}, limits: limits)
+      Process.sleep(300)
+      {:ok, {:function_call, _, snap2, _}} = ExMonty.resume(snap1, {:ok, nil})
+      assert {:ok, {:complete, nil, _}} = ExMonty.resume(snap2, {:ok, nil})
+    end
+
+    test "wall budget counts time spent between steps" do
+      {:ok, runner} =
+        ExMonty.compile("fetch()\nwhile True:\n    pass", external_functions: ["fetch"])
+      limits = %{max_duration_secs: 0.2, time_budget: :wall}
+
+      {:ok, {:function_call, _, snapshot, _}} = ExMonty.start(runner, %{}, limits: limits)
+      Process.sleep(300)
+
+      assert {:error, %ExMonty.Exception{limit: {:time_limit, _, elapsed}}} =
+               ExMonty.resume(snapshot, {:ok, nil})
+
+      assert elapsed >= 0.3
+    end
+
+    test "the budget counts wall time by default" do
+      {:ok, runner} =
+        ExMonty.compile("fetch()\nwhile True:\n    pass", external_functions: ["fetch"])
+
+      {:ok, {:function_call, _, snapshot, _}} =
+        ExMonty.start(runner, %{}, limits: %{max_duration_secs: 0.2})
+
+      Process.sleep(300)
+
+      assert {:error, %ExMonty.Exception{limit: {:time_limit, _, elapsed}}} =
+               ExMonty.resume(snapshot, {:ok, nil})
+
+      assert elapsed >= 0.3
+    end
+
+    test "cpu budget is shared by all steps" do
+      code = """
+      for _ in range(1000):
+          fetch()
+          i = 0
+          while i < 20000:
+              i += 1
+      """
+
+      {:ok, runner} = ExMonty.compile(code, external_functions: ["fetch"])
+      limits = %{max_duration_secs: 0.2, time_budget: :cpu}
+      {:ok, progress} = ExMonty.start(runner, %{}, limits: limits)
+
+      assert {:error, %ExMonty.Exception{limit: {:time_limit, _, _}}} = drive(progress)
+    end
+
+    test "remaining_budget reports what is left" do
+      {:ok, runner} = ExMonty.compile("print('hi')\nfetch()", external_functions: ["fetch"])
+
+      {:ok, {:function_call, _, snapshot, _}} =
+        ExMonty.start(runner, %{}, limits: %{max_duration_secs: 5.0, max_output_bytes: 100})
+
+      remaining = ExMonty.remaining_budget(snapshot)
+      assert remaining.time_secs > 4.0 and remaining.time_secs <= 5.0
+      assert remaining.output_bytes == 97
+      assert remaining.memory == nil
+
+      # Reading the budget does not consume the snapshot.
+      assert {:ok, {:complete, nil, _}} = ExMonty.resume(snapshot, {:ok, nil})
+    end
+  end
+
+  describe "usage stats" do
+    test "run returns a usage map with stats: true" do
+      code = """
+      def depth(n):
+          return 0 if n == 0 else depth(n - 1)
+
+      depth(10)
+      [[i] for i in range(100)]
+      """
+
+      {:ok, runner} = ExMonty.compile(code)
+      assert {:ok, _result, "", stats} = ExMonty.run(runner, %{}, stats: true)
+
+      assert stats.allocations > 0
+      assert stats.peak_memory >= stats.memory
+      assert stats.max_recursion_depth >= 10
+      assert is_float(stats.elapsed_secs)
+      assert is_float(stats.cpu_secs)
+    end
+
+    test "stats are cumulative across interactive steps" do
+      code = """
+      a = [[i] for i in range(50)]
+      fetch()
+      b = [[i] for i in range(50)]
+      """
+
+      {:ok, runner} = ExMonty.compile(code, external_functions: ["fetch"])
+
+      {:ok, {:function_call, _call, snapshot, _output, first}} =
+        ExMonty.start(runner, %{}, stats: true)
+
+      {:ok, {:complete, nil, _output, second}} =
+        ExMonty.resume(snapshot, {:ok, nil}, stats: true)
+
+      assert second.allocations > first.allocations
+      assert second.elapsed_secs >= first.elapsed_secs
+      assert second.cpu_secs >= first.cpu_secs
+    end
+
+    test "stats are off by default" do
+      assert {:ok, 4, ""} = ExMonty.eval("2 + 2")
+    end
+  end
+
+  defp drive({:function_call, _call, snapshot, _output}) do
+    case ExMonty.resume(snapshot, {:ok, nil}) do
+      {:ok, progress} -> drive(progress)
+      error -> error
+    end
+  end
+
+  defp drive(other), do: {:ok, other}
 end
@@ -27,6 +27,20 @@ defmodule ExMonty.SandboxTest do
       assert result == 25
     end
 
+    test "atom policy applies to handler results" do
+      functions = %{"status" => fn [], _kwargs -> {:ok, :ok} end}
+
+      assert {:ok, {false, :ok}, ""} =
+               ExMonty.Sandbox.run("s = status()\n(s == 'ok', s)",
+                 functions: functions,
+                 atom_policy: :tagged
+               )
+
+      assert {:error,
+              %ExMonty.Error{kind: :input_error, message: "unsupported atom :ok at result"}} =
+               ExMonty.Sandbox.run("status()", functions: functions, atom_policy: :strict)
+    end
+
     test "function returning error" do
       code = """
       try:
@@ -46,6 +60,21 @@ defmodule ExMonty.SandboxTest do
       assert result == "network error"
     end
 
+    test "function returning exception struct" do
+      code = """
+      try:
+          lookup('x')
+      except ValueError as e:
+          result = str(e)
+      result
+      """
+
+      error = %ExMonty.Exception{type: :value_error, args: [1]}
+
+      assert {:ok, "1", _output} =
+               ExMonty.Sandbox.run(code, functions: %{"lookup" => fn _, _ -> {:error, error} end})
+    end
+
     test "function with string processing" do
       {:ok, result, _output} =
         ExMonty.Sandbox.run(
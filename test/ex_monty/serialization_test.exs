@@ -45,5 +45,48 @@ defmodule ExMonty.SerializationTest do
       {:ok, final} = ExMonty.resume(restored, {:ok, "response"})
       assert {:complete, "response", _} = final
     end
+
+    test "handles survive dump/load" do
+      {:ok, runner} =
+        ExMonty.compile("notify(pid, 'a')\nnotify(pid, 'b')",
+          inputs: ["pid"],
+          external_functions: ["notify"]
+        )
+
+      pid = self()
+      {:ok, {:function_call, call, snapshot, _}} = ExMonty.start(runner, %{"pid" => pid})
+      assert call.args == [pid, "a"]
+
+      {:ok, binary} = ExMonty.dump_snapshot(snapshot)
+      {:ok, restored} = ExMonty.load_snapshot(binary)
+
+      assert {:ok, {:function_call, call, _, _}} = ExMonty.resume(restored, {:ok, nil})
+      assert call.args == [pid, "b"]
+    end
+
+    test "dumping a consumed snapshot fails" do
+      {:ok, runner} = ExMonty.compile("fetch('url')", external_functions: ["fetch"])
+      {:ok, {:function_call, _call, snapshot, _}} = ExMonty.start(runner)
+      {:ok, _binary} = ExMonty.dump_snapshot(snapshot)
+
+      assert {:error, %ExMonty.Error{kind: :consumed_snapshot}} = ExMonty.dump_snapshot(snapshot)
+    end
+
+    test "loading an invalid binary fails" do
+      assert {:error, %ExMonty.Error{kind: :invalid_snapshot}} =
+               ExMonty.load_snapshot(<<1, 2, 3>>)
+
+      assert {:error, %ExMonty.Error{kind: :invalid_snapshot}} = ExMonty.load_runner(<<>>)
+    end
+
+    test "loading a dump of another version fails with its version" do
+      {:ok, runner} = ExMonty.compile("fetch('url')", external_functions: ["fetch"])
+      {:ok, {:function_call, _call, snapshot, _}} = ExMonty.start(runner)
+      {:ok, <<"ExMonty", 1, body::binary>>} = ExMonty.dump_snapshot(snapshot)
+
+      assert {:error,
+              %ExMonty.Error{kind: :unsupported_snapshot_version, details: %{version: 255}}} =
+               ExMonty.load_snapshot(<<"ExMonty", 255, body::binary>>)
+    end
   end
 end
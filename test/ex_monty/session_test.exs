@@ -0,0 +1,93 @@
+defmodule ExMonty.SessionTest do
+  use ExUnit.Case
+
+  test "globals persist between cells" do
+    {:ok, session} = ExMonty.session_new()
+
+    assert {:ok, {:complete, nil, ""}} = ExMonty.session_feed(session, "x = 20")
+
+    assert {:ok, {:complete, nil, ""}} =
+             ExMonty.session_feed(session, "def inc(n):\n    return n + 1")
+
+    assert {:ok, {:complete, 21, "21\n"}} = ExMonty.session_feed(session, "print(inc(x))\ninc(x)")
+  end
+
+  test "an exception fails the cell but keeps the session" do
+    {:ok, session} = ExMonty.session_new()
+    {:ok, _} = ExMonty.session_feed(session, "x = 1")
+
+    assert {:error, %ExMonty.Exception{type: :name_error}} =
+             ExMonty.session_feed(session, "y = undefined_name")
+
+    assert {:ok, {:complete, 1, ""}} = ExMonty.session_feed(session, "x")
+  end
+
+  test "cells can pause at external function calls" do
+    {:ok, session} = ExMonty.session_new(external_functions: ["fetch"])
+
+    assert {:ok, {:function_call, %{name: "fetch"}, snapshot, ""}} =
+             ExMonty.session_feed(session, "data = fetch()")
+
+    assert {:error, %ExMonty.Error{kind: :session_busy, message: "session is busy"}} =
+             ExMonty.session_feed(session, "1")
+    assert {:ok, {:complete, nil, ""}} = ExMonty.resume(snapshot, {:ok, [1, 2, 3]})
+    assert {:ok, {:complete, 3, ""}} = ExMonty.session_feed(session, "len(data)")
+  end
+
+  test "a paused cell can be abandoned by resuming with an error" do
+    {:ok, session} = ExMonty.session_new(external_functions: ["fetch"])
+    {:ok, {:function_call, _, snapshot, _}} = ExMonty.session_feed(session, "fetch()")
+
+    assert {:error, %ExMonty.Exception{}} =
+             ExMonty.resume(snapshot, {:error, :runtime_error, "abandoned"})
+
+    assert {:ok, {:complete, 2, ""}} = ExMonty.session_feed(session, "1 + 1")
+  end
+
+  test "a paused cell whose snapshot is dropped frees the session" do
+    {:ok, session} = ExMonty.session_new(external_functions: ["fetch"])
+    {:ok, _} = ExMonty.session_feed(session, "x = 1")
+
+    # Pause the cell without keeping the snapshot, then collect it
+    pause = fn ->
+      code = "x = 2\ntry:\n    fetch()\nexcept Exception:\n    fetch()"
+      {:ok, {:function_call, _, _, _}} = ExMonty.session_feed(session, code)
+      :ok
+    end
+
+    :ok = pause.()
+    :erlang.garbage_collect()
+
+    # The cell is failed where it paused, even though it catches exceptions
+    assert {:ok, {:complete, 2, ""}} = ExMonty.session_feed(session, "x")
+  end
+
+  test "limits apply to each cell" do
+    {:ok, session} = ExMonty.session_new(limits: %{max_output_bytes: 5})
+
+    assert {:ok, {:complete, nil, "abc\n"}} = ExMonty.session_feed(session, "print('abc')")
+    assert {:ok, {:complete, nil, "abc\n"}} = ExMonty.session_feed(session, "print('abc')")
+
+    assert {:error, %ExMonty.Exception{limit: {:output_limit, 5, _}}} =
+             ExMonty.session_feed(session, "print('abcdef')")
+  end
+
+  test "dump and load keep globals" do
+    {:ok, session} = ExMonty.session_new()
+    {:ok, _} = ExMonty.session_feed(session, "x = [1, 2]")
+
+    {:ok, binary} = ExMonty.dump_session(session)
+    {:ok, restored} = ExMonty.load_session(binary)
+
+    assert {:ok, {:complete, [1, 2, 3], ""}} = ExMonty.session_feed(restored, "x + [3]")
+    assert {:ok, {:complete, [1, 2], ""}} = ExMonty.session_feed(session, "x")
+  end
+
+  test "Sandbox.feed dispatches calls" do
+    {:ok, session} = ExMonty.session_new(external_functions: ["double"])
+    functions = %{"double" => fn [x], _kwargs -> {:ok, x * 2} end}
+
+    assert {:ok, nil, ""} = ExMonty.Sandbox.feed(session, "x = double(21)", functions: functions)
+    assert {:ok, 42, ""} = ExMonty.Sandbox.feed(session, "x", functions: functions)
+  end
+end
@@ -79,7 +79,7 @@ defmodule ExMontyTest do
       {:ok, runner} = ExMonty.compile("x - y", inputs: ["x", "y"])
 
       # Direct NIF call with intentionally reversed input order.
-      assert {-10, ""} = ExMonty.Native.run(runner, [{"y", 20}, {"x", 10}], nil)
+      assert {:ok, -10, ""} = ExMonty.Native.run(runner, [{"y", 20}, {"x", 10}], nil, nil)
 
       # High-level API should also work regardless of map enumeration order.
       assert {:ok, -10, ""} = ExMonty.run(runner, %{"x" => 10, "y" => 20})
@@ -88,8 +88,27 @@ defmodule ExMontyTest do
     test "missing and unexpected inputs return errors" do
       {:ok, runner} = ExMonty.compile("x + y", inputs: ["x", "y"])
 
-      assert {:error, _} = ExMonty.run(runner, %{"x" => 1})
-      assert {:error, _} = ExMonty.run(runner, %{"x" => 1, "y" => 2, "z" => 3})
+      assert {:error, %ExMonty.Error{kind: :input_error, details: %{missing: ["y"]}}} =
+               ExMonty.run(runner, %{"x" => 1})
+
+      assert {:error, %ExMonty.Error{kind: :input_error, details: %{unexpected: ["z"]}}} =
+               ExMonty.run(runner, %{"x" => 1, "y" => 2, "z" => 3})
+    end
+
+    test "malformed stats, cancel and print_to options are invalid options" do
+      {:ok, runner} = ExMonty.compile("1")
+
+      assert {:error, %ExMonty.Error{kind: :invalid_option, details: %{option: :stats}}} =
+               ExMonty.run(runner, %{}, stats: :yes)
+
+      assert {:error, %ExMonty.Error{kind: :invalid_option, details: %{option: :cancel}}} =
+               ExMonty.run(runner, %{}, cancel: make_ref())
+
+      assert {:error, %ExMonty.Error{kind: :invalid_option, details: %{option: :print_to}}} =
+               ExMonty.run(runner, %{}, print_to: :stdout)
+
+      assert {:error, %ExMonty.Error{kind: :invalid_option, details: %{option: :print_to}}} =
+               ExMonty.run(runner, %{}, print_to: {:not_a_pid, nil})
     end
 
     test "runner is reusable" do
@@ -110,6 +129,19 @@ defmodule ExMontyTest do
       {:ok, runner} = ExMonty.compile("42")
       assert {:ok, 42, ""} = ExMonty.run(runner)
     end
+
+    test "compile errors carry diagnostics" do
+      assert {:error, %ExMonty.Exception{type: :syntax_error, diagnostics: [diagnostic]}} =
+               ExMonty.compile("x = 1\ny = (2 +")
+
+      assert %ExMonty.Diagnostic{line: 2, column: column, end_line: end_line} = diagnostic
+      assert is_integer(column) and end_line >= 2
+      assert is_binary(diagnostic.message) and diagnostic.message != ""
+    end
+
+    test "runtime errors have no diagnostics" do
+      assert {:error, %ExMonty.Exception{diagnostics: []}} = ExMonty.eval("1 / 0")
+    end
   end
 
   describe "type mapping" do
@@ -135,6 +167,33 @@ defmodule ExMontyTest do
       assert {:ok, 3.14, ""} = ExMonty.eval("3.14")
     end
 
+    test "non-finite floats" do
+      assert {:ok, :nan, ""} = ExMonty.eval("float('nan')")
+      assert {:ok, :infinity, ""} = ExMonty.eval("1e308 * 10")
+      assert {:ok, :neg_infinity, ""} = ExMonty.eval("float('-inf')")
+      assert {:ok, [:infinity, 1.5], ""} = ExMonty.eval("[float('inf'), 1.5]")
+    end
+
+    test "non-finite float inputs" do
+      opts = [float_atoms: :float]
+      assert {:ok, true, ""} = ExMonty.eval("x != x", [inputs: %{"x" => :nan}] ++ opts)
+      assert {:ok, true, ""} = ExMonty.eval("x > 1e308", [inputs: %{"x" => :infinity}] ++ opts)
+      assert {:ok, :neg_infinity, ""} = ExMonty.eval("-x", [inputs: %{"x" => :infinity}] ++ opts)
+    end
+
+    test "non-finite float atoms follow the atom policy by default" do
+      assert {:ok, "nan", ""} = ExMonty.eval("x", inputs: %{"x" => :nan})
+
+      assert {:error,
+              %ExMonty.Error{
+                kind: :input_error,
+                message: "unsupported atom :infinity at x"
+              }} = ExMonty.eval("x", inputs: %{"x" => :infinity}, atom_policy: :strict)
+
+      assert {:ok, :neg_infinity, ""} =
+               ExMonty.eval("x", inputs: %{"x" => :neg_infinity}, atom_policy: :tagged)
+    end
+
     test "string" do
       assert {:ok, "hello", ""} = ExMonty.eval("'hello'")
     end
@@ -181,6 +240,67 @@ defmodule ExMontyTest do
       assert result["b"] == 2
     end
 
+    test "dict as ordered pairs" do
+      code = "{'z': 1, 'a': {'y': 2, 'b': 3}}"
+
+      assert {:ok, [{"z", 1}, {"a", [{"y", 2}, {"b", 3}]}], ""} =
+               ExMonty.eval(code, dict_format: :pairs)
+
+      assert {:ok, {:dict, [{"z", 1}, {"a", {:dict, [{"y", 2}, {"b", 3}]}}]}, ""} =
+               ExMonty.eval(code, dict_format: :tagged)
+    end
+
+    test "tagged dict input keeps its order" do
+      {:ok, runner} = ExMonty.compile("list(x.keys())", inputs: ["x"])
+      dict = {:dict, [{"z", 1}, {"a", 2}, {"m", 3}]}
+      assert {:ok, ["z", "a", "m"], ""} = ExMonty.run(runner, %{"x" => dict})
+
+      {:ok, runner} = ExMonty.compile("x", inputs: ["x"])
+      assert {:ok, ^dict, ""} = ExMonty.run(runner, %{"x" => dict}, dict_format: :tagged)
+    end
+
+    test "dict with colliding keys falls back to tagged pairs" do
+      code = "{float('nan'): 1, float('nan'): 2}"
+      assert {:ok, {:dict, [{:nan, 1}, {:nan, 2}]}, ""} = ExMonty.eval(code)
+      assert {:ok, [{:nan, 1}, {:nan, 2}], ""} = ExMonty.eval(code, dict_format: :pairs)
+    end
+
+    test "dict keys of different types that encode alike fall back to tagged pairs" do
+      # A tuple starting with a tagged :bytes atom encodes like a bytes key,
+      # and a tagged :nan atom like a NaN float.
+      {:ok, runner} =
+        ExMonty.compile("{(tag, b'a'): 1, b'a': 2, nan: 3, float('nan'): 4}",
+          inputs: ["tag", "nan"]
+        )
+
+      inputs = %{"tag" => :bytes, "nan" => :nan}
+      pairs = [{{:bytes, "a"}, 1}, {{:bytes, "a"}, 2}, {:nan, 3}, {:nan, 4}]
+
+      assert {:ok, {:dict, ^pairs}, ""} = ExMonty.run(runner, inputs, atom_policy: :tagged)
+
+      assert {:ok, ^pairs, ""} =
+               ExMonty.run(runner, inputs, atom_policy: :tagged, dict_format: :pairs)
+
+      assert {:ok, {:dict, ^pairs}, ""} =
+               ExMonty.run(runner, inputs, atom_policy: :tagged, dict_format: :tagged)
+    end
+
+    test "equal Python keys stay one dict entry in every format" do
+      code = "{1: 'a', 1.0: 'b', True: 'c'}"
+      assert {:ok, %{1 => "c"}, ""} = ExMonty.eval(code)
+      assert {:ok, [{1, "c"}], ""} = ExMonty.eval(code, dict_format: :pairs)
+      assert {:ok, {:dict, [{1, "c"}]}, ""} = ExMonty.eval(code, dict_format: :tagged)
+    end
+
+    test "invalid dict_format" do
+      assert {:error,
+              %ExMonty.Error{
+                kind: :invalid_option,
+                message: "invalid dict_format: expected one of :map, :pairs, :tagged, got: :list"
+              }} =
+               ExMonty.eval("{}", dict_format: :list)
+    end
+
     test "empty dict" do
       assert {:ok, result, ""} = ExMonty.eval("{}")
       assert result == %{}
@@ -191,6 +311,199 @@ defmodule ExMontyTest do
       assert is_struct(result, MapSet) or is_map(result)
     end
 
+    test "frozenset" do
+      assert {:ok, {:frozenset, set}, ""} = ExMonty.eval("frozenset([1, 2, 3])")
+      assert set == MapSet.new([1, 2, 3])
+    end
+
+    test "frozenset input roundtrip" do
+      {:ok, runner} = ExMonty.compile("{x: 'ok'}[x]", inputs: ["x"])
+      frozen = {:frozenset, MapSet.new(["a", "b"])}
+      assert {:ok, "ok", ""} = ExMonty.run(runner, %{"x" => frozen})
+
+      {:ok, runner} = ExMonty.compile("x", inputs: ["x"])
+      assert {:ok, ^frozen, ""} = ExMonty.run(runner, %{"x" => frozen})
+      assert {:ok, %MapSet{}, ""} = ExMonty.run(runner, %{"x" => MapSet.new(["a"])})
+    end
+
+    test "dataclass input roundtrip" do
+      point = %ExMonty.Dataclass{
+        name: "Point",
+        fields: %{"x" => 1, "y" => 2},
+        field_names: ["y", "x"],
+        frozen: true
+      }
+
+      {:ok, runner} = ExMonty.compile("(p.x + p.y, repr(p), p)", inputs: ["p"])
+
+      assert {:ok, {3, "Point(y=2, x=1)", %ExMonty.Dataclass{} = result}, ""} =
+               ExMonty.run(runner, %{"p" => point})
+
+      assert %{name: "Point", fields: %{"x" => 1, "y" => 2}, field_names: ["y", "x"]} = result
+      assert result.frozen
+    end
+
+    test "exception value roundtrip" do
+      assert {:ok, [%ExMonty.Exception{type: :value_error, message: "bad", traceback: []}], ""} =
+               ExMonty.eval("[ValueError('bad')]")
+
+      {:ok, runner} = ExMonty.compile("(type(e).__name__, str(e), e)", inputs: ["e"])
+      exc = %ExMonty.Exception{type: :key_error, message: "missing"}
+
+      assert {:ok, {"KeyError", _, %ExMonty.Exception{type: :key_error}}, ""} =
+               ExMonty.run(runner, %{"e" => exc})
+    end
+
+    test "exception value with unknown type" do
+      {:ok, runner} = ExMonty.compile("e", inputs: ["e"])
+      exc = %ExMonty.Exception{type: :not_an_error, message: "x"}
+
+      assert {:error,
+              %ExMonty.Error{
+                kind: :input_error,
+                message: "unknown exception type :not_an_error at e"
+              }} =
+               ExMonty.run(runner, %{"e" => exc})
+    end
+
+    test "pids, refs and funs pass through as handles" do
+      {:ok, runner} = ExMonty.compile("[x, y, x, f, repr(x)]", inputs: ["x", "y", "f"])
+      ref = make_ref()
+      pid = self()
+      fun = fn -> :ok end
+
+      assert {:ok, [^ref, ^pid, ^ref, ^fun, "<Handle #1>"], ""} =
+               ExMonty.run(runner, %{"x" => ref, "y" => pid, "f" => fun})
+    end
+
+    test "handle reprs cannot be passed in" do
+      {:ok, runner} = ExMonty.compile("[x, h]", inputs: ["x", "h"])
+
+      assert {:error,
+              %ExMonty.Error{
+                kind: :input_error,
+                message: "handle reprs cannot be passed in at h"
+              }} =
+               ExMonty.run(runner, %{"x" => make_ref(), "h" => {:repr, "<Handle #1>"}})
+    end
+
+    test "dataclasses cannot claim the reserved type ids" do
+      {:ok, runner} = ExMonty.compile("d", inputs: ["d"])
+
+      for type_id <- [18_446_744_073_709_551_615, 18_446_744_073_709_551_614] do
+        dc = %ExMonty.Dataclass{
+          name: "Atom",
+          type_id: type_id,
+          fields: %{"name" => "ok"},
+          field_names: ["name"],
+          frozen: true
+        }
+
+        message = "reserved dataclass type_id #{type_id} at d"
+
+        assert {:error, %ExMonty.Error{kind: :input_error, message: ^message}} =
+                 ExMonty.run(runner, %{"d" => dc})
+      end
+    end
+
+    test "atoms decode as strings by default" do
+      assert {:ok, "ok", ""} = ExMonty.eval("x", inputs: %{"x" => :ok})
+    end
+
+    test "strict atom policy rejects atoms with their path" do
+      assert {:error,
+              %ExMonty.Error{
+                kind: :input_error,
+                message: "unsupported atom :error at x[1][\"status\"]"
+              }} =
+               ExMonty.eval("x",
+                 inputs: %{"x" => [1, %{"status" => :error}]},
+                 atom_policy: :strict
+               )
+
+      assert {:ok, [nil, true], ""} =
+               ExMonty.eval("x", inputs: %{"x" => [nil, true]}, atom_policy: :strict)
+    end
+
+    test "tagged atoms stay distinct from strings and round-trip" do
+      code = "(x == 'ok', repr(x), x, {x: 1})"
+
+      assert {:ok, {false, "Atom(name='ok')", :ok, %{ok: 1}}, ""} =
+               ExMonty.eval(code, inputs: %{"x" => :ok}, atom_policy: :tagged)
+    end
+
+    test "tagged :ok and :error results are not mistaken for status tuples" do
+      assert {:ok, :error, ""} = ExMonty.eval("x", inputs: %{"x" => :error}, atom_policy: :tagged)
+      assert {:ok, :ok, ""} = ExMonty.eval("x", inputs: %{"x" => :ok}, atom_policy: :tagged)
+    end
+
+    test "keyword lists and charlists stay lists by default" do
+      assert {:ok, [{"a", 1}], ""} = ExMonty.eval("x", inputs: %{"x" => [a: 1]})
+      assert {:ok, [97, 98], ""} = ExMonty.eval("x", inputs: %{"x" => ~c"ab"})
+    end
+
+    test "keyword lists as dicts" do
+      assert {:ok, {1, %{"a" => 1, "b" => [2]}}, ""} =
+               ExMonty.eval("(x['a'], x)",
+                 inputs: %{"x" => [a: 1, b: [2], a: 3]},
+                 keyword_lists: :dict
+               )
+    end
+
+    test "charlists as strings" do
+      assert {:ok, {"héllo", [1, 2]}, ""} =
+               ExMonty.eval("x",
+                 inputs: %{"x" => {~c"héllo", [1, 2]}},
+                 charlists: :string
+               )
+    end
+
+    test "improper lists name their path" do
+      assert {:error,
+              %ExMonty.Error{kind: :input_error, message: "improper list at x[\"items\"][1]"}} =
+               ExMonty.eval("x", inputs: %{"x" => %{"items" => [1, [2 | 3]]}})
+    end
+
+    test "dates and times roundtrip as named tuples" do
+      {:ok, runner} = ExMonty.compile("x", inputs: ["x"])
+
+      for value <- [
+            ~D[2024-01-02],
+            ~T[10:20:30.123],
+            ~N[2024-01-02 10:20:30],
+            ~U[2024-01-02 10:20:30.000001Z]
+          ] do
+        assert {:ok, ^value, ""} = ExMonty.run(runner, %{"x" => value})
+      end
+    end
+
+    test "date fields are readable in python" do
+      assert {:ok, {2024, 1, 2, "Date(year=2024, month=1, day=2)"}, ""} =
+               ExMonty.eval("(x.year, x.month, x[2], repr(x))",
+                 inputs: %{"x" => ~D[2024-01-02]}
+               )
+    end
+
+    test "decimal roundtrip" do
+      {:ok, runner} = ExMonty.compile("(x.sign * x.coef, x.exp, x)", inputs: ["x"])
+      decimal = %{__struct__: Decimal, sign: -1, coef: 12345, exp: -2}
+      nan = %{__struct__: Decimal, sign: 1, coef: :NaN, exp: 0}
+
+      assert {:ok, {-12345, -2, ^decimal}, ""} = ExMonty.run(runner, %{"x" => decimal})
+      assert {:ok, {_, 0, ^nan}, ""} = ExMonty.run(runner, %{"x" => nan})
+    end
+
+    test "non-ISO calendars are rejected" do
+      date = %{~D[2024-01-02] | calendar: Calendar.Julian}
+
+      assert {:error,
+              %ExMonty.Error{
+                kind: :input_error,
+                message: "unsupported calendar Elixir.Calendar.Julian in %Date{} at x"
+              }} =
+               ExMonty.eval("x", inputs: %{"x" => date})
+    end
+
     test "bytes input roundtrip" do
       {:ok, runner} = ExMonty.compile("x", inputs: ["x"])
 
@@ -250,6 +563,27 @@ defmodule ExMontyTest do
       assert {:ok, nil, output} = ExMonty.eval("print(f'{1 + 2} items')")
       assert output == "3 items\n"
     end
+
+    test "streams lines to print_to while running" do
+      code = """
+      print('line 1')
+      print('line 2', end='')
+      """
+
+      ref = make_ref()
+      assert {:ok, nil, "line 1\nline 2"} = ExMonty.eval(code, print_to: {self(), ref})
+      assert_received {:ex_monty_print, ^ref, "line 1\n"}
+      assert_received {:ex_monty_print, ^ref, "line 2"}
+    end
+
+    test "print_to with a bare pid uses nil as ref" do
+      assert {:ok, nil, "hi\n"} = ExMonty.eval("print('hi')", print_to: self())
+      assert_received {:ex_monty_print, nil, "hi\n"}
+
+      {:ok, runner} = ExMonty.compile("print('native')")
+      assert {:ok, nil, "native\n"} = ExMonty.Native.run(runner, [], nil, %{print_to: self()})
+      assert_received {:ex_monty_print, nil, "native\n"}
+    end
   end
 
   describe "error handling" do
@@ -284,5 +618,36 @@ defmodule ExMontyTest do
       {:error, exc} = ExMonty.eval("1 / 0")
       assert is_list(exc.traceback)
     end
+
+    test "traceback frames carry their source line" do
+      {:error, exc} = ExMonty.eval("x = 1\ny = x / 0")
+      frame = List.last(exc.traceback)
+
+      assert %ExMonty.StackFrame{line: 2, source: "y = x / 0", caret: {first, last}} = frame
+      assert 1 <= first and first <= last and last <= 9
+
+      assert ExMonty.Exception.format(exc) =~
+               ~r/\ATraceback \(most recent call last\):\n  File "[^"]+", line 2, in <module>\n    y = x \/ 0\n/
+    end
+
+    test "errors share one taxonomy of kinds" do
+      assert {:error, %ExMonty.Exception{kind: :python_exception}} = ExMonty.eval("1 / 0")
+
+      assert {:error, %ExMonty.Exception{kind: :limit_exceeded, limit: {:recursion_limit, _, _}}} =
+               ExMonty.eval("def f(n):\n    return f(n + 1)\nf(0)",
+                 limits: %{max_recursion_depth: 50}
+               )
+
+      assert {:error, %ExMonty.Exception{kind: :compile_error}} = ExMonty.compile("x = (1 +")
+
+      assert {:error, %ExMonty.Error{kind: :input_error, details: %{path: "x[1]"}}} =
+               ExMonty.eval("x", inputs: %{"x" => [1, :ok]}, atom_policy: :strict)
+
+      assert {:error, %ExMonty.Error{kind: :invalid_option, details: %{option: :max_memory}}} =
+               ExMonty.eval("1", limits: %{max_memory: 0})
+
+      assert {:error, %ExMonty.Error{kind: :invalid_option, details: %{option: :inputs}}} =
+               ExMonty.compile("x", inputs: ["x", "x"])
+    end
   end
 end